@@ -0,0 +1,391 @@
+use std::fmt::Write;
+
+use pretty::{Doc, RcDoc};
+
+use crate::shared::{
+    ast::{parse_position, parse_var, Asm, Op, Triv, Var},
+    index,
+    read::{expected, malformed, parse_all, Sexp},
+    Parse, ToDoc, Verify,
+};
+
+pub type Label = usize;
+
+// Blocks are numbered densely from the entry block, `0`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub term: Terminator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Let(Var, Rhs),
+    SetGlobal(Var, Triv),
+    SetIndex(Var, Triv, Triv),
+    Asm(Asm),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rhs {
+    Triv(Triv),
+    Binop(Var, Op, Triv),
+    Global(Var),
+    Index(Var, Triv),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(Label),
+    Branch(Var, Op, Triv, Label, Label),
+    // Calls end a block so the return point is always the start of `next`.
+    Call {
+        dest: Option<Var>,
+        subject: Triv,
+        args: Vec<Triv>,
+        next: Label,
+    },
+    Return(Triv),
+}
+
+impl Verify for Cfg {
+    fn verify(&self) -> Result<(), String> {
+        if self.blocks.is_empty() {
+            return Err("no entry block".to_string());
+        }
+        for (label, block) in self.blocks.iter().enumerate() {
+            block.term.verify(label, self.blocks.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl Terminator {
+    pub fn verify(&self, label: Label, len: usize) -> Result<(), String> {
+        if let Terminator::Branch(_, op, _, _, _) = self {
+            if !op.is_comparison() {
+                return Err(format!("b{} branches on arithmetic", label));
+            }
+        }
+        match self.successors().into_iter().find(|succ| *succ >= len) {
+            Some(succ) => Err(format!("b{} jumps to missing block b{}", label, succ)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn successors(&self) -> Vec<Label> {
+        match self {
+            Terminator::Jump(l) => vec![*l],
+            Terminator::Branch(_, _, _, conseq, alt) => vec![*conseq, *alt],
+            Terminator::Call { next, .. } => vec![*next],
+            Terminator::Return(_) => vec![],
+        }
+    }
+
+    pub fn relabel(self, relabel: impl Fn(Label) -> Label) -> Terminator {
+        match self {
+            Terminator::Jump(l) => Terminator::Jump(relabel(l)),
+            Terminator::Branch(x, op, rhs, conseq, alt) => {
+                Terminator::Branch(x, op, rhs, relabel(conseq), relabel(alt))
+            }
+            Terminator::Call {
+                dest,
+                subject,
+                args,
+                next,
+            } => Terminator::Call {
+                dest,
+                subject,
+                args,
+                next: relabel(next),
+            },
+            term @ Terminator::Return(_) => term,
+        }
+    }
+}
+
+impl Cfg {
+    pub fn to_dot(&self, name: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph \"{}\" {{", name);
+        let _ = writeln!(out, "  node [shape=box, fontname=monospace];");
+        for (label, block) in self.blocks.iter().enumerate() {
+            let mut text = Vec::new();
+            let _ = block.to_doc().render(40, &mut text);
+            let text = String::from_utf8_lossy(&text)
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\l");
+            let _ = writeln!(out, "  b{} [label=\"b{}:\\l{}\\l\"];", label, label, text);
+            match &block.term {
+                Terminator::Branch(_, _, _, conseq, alt) => {
+                    let _ = writeln!(out, "  b{} -> b{} [label=\"T\"];", label, conseq);
+                    let _ = writeln!(out, "  b{} -> b{} [label=\"F\"];", label, alt);
+                }
+                term => {
+                    for succ in term.successors() {
+                        let _ = writeln!(out, "  b{} -> b{};", label, succ);
+                    }
+                }
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+pub fn label_doc<'a>(l: Label) -> RcDoc<'a, ()> {
+    RcDoc::text(format!("b{}", l))
+}
+
+impl ToDoc for Cfg {
+    fn to_doc(&self) -> RcDoc<'_> {
+        let blocks = self.blocks.iter().enumerate().map(|(label, block)| {
+            RcDoc::text("(")
+                .append(label_doc(label))
+                .append(RcDoc::line().append(block.to_doc()).nest(2))
+                .append(RcDoc::text(")"))
+                .group()
+        });
+        RcDoc::text("(cfg")
+            .append(
+                RcDoc::line()
+                    .append(RcDoc::intersperse(blocks, Doc::line()))
+                    .nest(2),
+            )
+            .append(RcDoc::text(")"))
+    }
+}
+
+impl ToDoc for Block {
+    fn to_doc(&self) -> RcDoc<'_> {
+        let lines = self
+            .stmts
+            .iter()
+            .map(|stmt| stmt.to_doc())
+            .chain([self.term.to_doc()]);
+        RcDoc::intersperse(lines, Doc::line())
+    }
+}
+
+impl ToDoc for Stmt {
+    fn to_doc(&self) -> RcDoc<'_> {
+        match self {
+            Stmt::Let(x, rhs) => {
+                let args = [RcDoc::text(x), rhs.to_doc()];
+                RcDoc::text("(set! ")
+                    .append(RcDoc::intersperse(args, Doc::line()).nest(2).group())
+                    .append(RcDoc::text(")"))
+            }
+            Stmt::SetGlobal(x, t) => {
+                let args = [RcDoc::text(x), t.to_doc()];
+                RcDoc::text("(set-global! ")
+                    .append(RcDoc::intersperse(args, Doc::line()).nest(2).group())
+                    .append(RcDoc::text(")"))
+            }
+            Stmt::SetIndex(a, i, t) => {
+                let args = [index(a, i.to_doc()), t.to_doc()];
+                RcDoc::text("(set! ")
+                    .append(RcDoc::intersperse(args, Doc::line()).nest(2).group())
+                    .append(RcDoc::text(")"))
+            }
+            Stmt::Asm(asm) => asm.to_doc(),
+        }
+    }
+}
+
+impl ToDoc for Rhs {
+    fn to_doc(&self) -> RcDoc<'_> {
+        match self {
+            Rhs::Triv(t) => t.to_doc(),
+            Rhs::Binop(x, op, rhs) => binop_doc(x, op, rhs),
+            Rhs::Global(x) => RcDoc::text(format!("(global {})", x)),
+            Rhs::Index(a, i) => index(a, i.to_doc()),
+        }
+    }
+}
+
+fn binop_doc<'a>(x: &'a Var, op: &Op, rhs: &'a Triv) -> RcDoc<'a, ()> {
+    RcDoc::text("(")
+        .append(
+            RcDoc::intersperse(
+                [
+                    RcDoc::text(format!("{:?}", op)),
+                    RcDoc::text(x),
+                    rhs.to_doc(),
+                ],
+                Doc::line(),
+            )
+            .group(),
+        )
+        .append(RcDoc::text(")"))
+}
+
+impl ToDoc for Terminator {
+    fn to_doc(&self) -> RcDoc<'_> {
+        match self {
+            Terminator::Jump(l) => RcDoc::text("(jump ")
+                .append(label_doc(*l))
+                .append(RcDoc::text(")")),
+            Terminator::Branch(x, op, rhs, conseq, alt) => {
+                let args = [binop_doc(x, op, rhs), label_doc(*conseq), label_doc(*alt)];
+                RcDoc::text("(branch ")
+                    .append(RcDoc::intersperse(args, Doc::line()).nest(2).group())
+                    .append(RcDoc::text(")"))
+            }
+            Terminator::Call {
+                dest,
+                subject,
+                args,
+                next,
+            } => {
+                let call = RcDoc::text("(")
+                    .append(
+                        RcDoc::intersperse(
+                            [subject.to_doc()]
+                                .into_iter()
+                                .chain(args.iter().map(|arg| arg.to_doc())),
+                            Doc::line(),
+                        )
+                        .group(),
+                    )
+                    .append(RcDoc::text(")"));
+                let dest = match dest {
+                    Some(x) => RcDoc::text(x),
+                    None => RcDoc::text("_"),
+                };
+                RcDoc::text("(call ")
+                    .append(
+                        RcDoc::intersperse([dest, call, label_doc(*next)], Doc::line())
+                            .nest(2)
+                            .group(),
+                    )
+                    .append(RcDoc::text(")"))
+            }
+            Terminator::Return(t) => RcDoc::text("(return ")
+                .append(t.to_doc())
+                .append(RcDoc::text(")")),
+        }
+    }
+}
+
+pub fn parse_label(sexp: &Sexp) -> Result<Label, String> {
+    match sexp.atom().and_then(|a| a.strip_prefix('b')) {
+        Some(n) => n.parse().or_else(|_| expected("a block label", sexp)),
+        None => expected("a block label", sexp),
+    }
+}
+
+// Blocks are listed in label order, so each one's label has to be its position.
+impl Parse for Cfg {
+    fn parse(sexp: &Sexp) -> Result<Cfg, String> {
+        let blocks = match sexp.form() {
+            Some(("cfg", blocks)) => blocks,
+            _ => return expected("(cfg block ...)", sexp),
+        };
+        let blocks = blocks
+            .iter()
+            .enumerate()
+            .map(|(label, block)| match block.list() {
+                Some([l, stmts @ .., term]) if parse_label(l) == Ok(label) => Ok(Block {
+                    stmts: parse_all(stmts)?,
+                    term: Terminator::parse(term)?,
+                }),
+                _ => expected(&format!("(b{} stmt ... terminator)", label), block),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Cfg { blocks })
+    }
+}
+
+impl Parse for Stmt {
+    fn parse(sexp: &Sexp) -> Result<Stmt, String> {
+        match sexp.form() {
+            Some(("set!", [target, rhs])) => match target.form() {
+                Some(("index", [a, i])) => Ok(Stmt::SetIndex(
+                    parse_var(a)?,
+                    Triv::parse(i)?,
+                    Triv::parse(rhs)?,
+                )),
+                _ => Ok(Stmt::Let(parse_var(target)?, Rhs::parse(rhs)?)),
+            },
+            Some(("set-global!", [x, t])) => Ok(Stmt::SetGlobal(parse_var(x)?, Triv::parse(t)?)),
+            Some(("asm", _)) => Asm::parse(sexp).map(Stmt::Asm),
+            _ => expected(
+                "(set! var rhs), (set-global! global value) or (asm ...)",
+                sexp,
+            ),
+        }
+    }
+}
+
+impl Parse for Rhs {
+    fn parse(sexp: &Sexp) -> Result<Rhs, String> {
+        match sexp.list() {
+            Some([head, a, i]) if head.atom() == Some("index") => {
+                Ok(Rhs::Index(parse_var(a)?, Triv::parse(i)?))
+            }
+            Some([op, x, rhs]) => {
+                let op = Op::parse(op)?;
+                let rhs = parse_operand(&op, rhs)?;
+                Ok(Rhs::Binop(parse_var(x)?, op, rhs))
+            }
+            Some([head, x]) if head.atom() == Some("global") => Ok(Rhs::Global(parse_var(x)?)),
+            Some(_) => malformed(sexp),
+            None => Triv::parse(sexp).map(Rhs::Triv),
+        }
+    }
+}
+
+fn parse_operand(op: &Op, sexp: &Sexp) -> Result<Triv, String> {
+    if op.takes_position() {
+        parse_position(op, sexp).map(Triv::Value)
+    } else {
+        Triv::parse(sexp)
+    }
+}
+
+impl Parse for Terminator {
+    fn parse(sexp: &Sexp) -> Result<Terminator, String> {
+        match sexp.form() {
+            Some(("jump", [l])) => Ok(Terminator::Jump(parse_label(l)?)),
+            Some(("branch", [test, conseq, alt])) => match test.list() {
+                Some([op, x, rhs]) => {
+                    let op = Op::parse(op)?;
+                    let rhs = parse_operand(&op, rhs)?;
+                    Ok(Terminator::Branch(
+                        parse_var(x)?,
+                        op,
+                        rhs,
+                        parse_label(conseq)?,
+                        parse_label(alt)?,
+                    ))
+                }
+                _ => expected("a comparison", test),
+            },
+            Some(("call", [dest, call, next])) => {
+                let (subject, args) = match call.list() {
+                    Some([subject, args @ ..]) => (Triv::parse(subject)?, parse_all(args)?),
+                    _ => return expected("(subject arg ...)", call),
+                };
+                let dest = match dest.atom() {
+                    Some("_") => None,
+                    _ => Some(parse_var(dest)?),
+                };
+                Ok(Terminator::Call {
+                    dest,
+                    subject,
+                    args,
+                    next: parse_label(next)?,
+                })
+            }
+            Some(("return", [t])) => Ok(Terminator::Return(Triv::parse(t)?)),
+            _ => expected("a terminator", sexp),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeSet;
+
+use crate::build_cfg::ast::Label;
+
+// Dominator tree and dominance frontiers for a graph given as successor lists, with
+// `0` as the entry and every block reachable from it. Uses the Cooper-Harvey-Kennedy
+// iteration over reverse postorder.
+pub struct Dominators {
+    pub children: Vec<Vec<Label>>,
+    pub frontiers: Vec<BTreeSet<Label>>,
+}
+
+impl Dominators {
+    pub fn new(succs: &[Vec<Label>]) -> Dominators {
+        let n = succs.len();
+        let order = reverse_postorder(succs);
+        let mut position = vec![usize::MAX; n];
+        for (i, label) in order.iter().enumerate() {
+            position[*label] = i;
+        }
+        let preds = predecessors(succs);
+
+        let mut idom: Vec<Option<Label>> = vec![None; n];
+        idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &label in order.iter().skip(1) {
+                let mut new_idom = None;
+                for &pred in &preds[label] {
+                    if idom[pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(other) => intersect(&idom, &position, pred, other),
+                    });
+                }
+                if new_idom.is_some() && idom[label] != new_idom {
+                    idom[label] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom[0] = None;
+
+        let mut children = vec![vec![]; n];
+        for (label, parent) in idom.iter().enumerate() {
+            if let Some(parent) = parent {
+                children[*parent].push(label);
+            }
+        }
+
+        let mut frontiers = vec![BTreeSet::new(); n];
+        for (label, label_preds) in preds.iter().enumerate() {
+            if label_preds.len() < 2 {
+                continue;
+            }
+            for &pred in label_preds {
+                let mut runner = pred;
+                while Some(runner) != idom[label] {
+                    frontiers[runner].insert(label);
+                    match idom[runner] {
+                        Some(up) => runner = up,
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        Dominators {
+            children,
+            frontiers,
+        }
+    }
+}
+
+fn intersect(idom: &[Option<Label>], position: &[usize], mut a: Label, mut b: Label) -> Label {
+    while a != b {
+        while position[a] > position[b] {
+            a = idom[a].expect("processed block has an idom");
+        }
+        while position[b] > position[a] {
+            b = idom[b].expect("processed block has an idom");
+        }
+    }
+    a
+}
+
+pub fn predecessors(succs: &[Vec<Label>]) -> Vec<Vec<Label>> {
+    let mut preds = vec![vec![]; succs.len()];
+    for (label, label_succs) in succs.iter().enumerate() {
+        for &succ in label_succs {
+            if !preds[succ].contains(&label) {
+                preds[succ].push(label);
+            }
+        }
+    }
+    preds
+}
+
+pub fn reverse_postorder(succs: &[Vec<Label>]) -> Vec<Label> {
+    let mut seen = vec![false; succs.len()];
+    let mut postorder = vec![];
+    // (block, whether its successors have been pushed yet)
+    let mut stack = vec![(0, false)];
+    while let Some((label, expanded)) = stack.pop() {
+        if expanded {
+            postorder.push(label);
+            continue;
+        }
+        if seen[label] {
+            continue;
+        }
+        seen[label] = true;
+        stack.push((label, true));
+        for &succ in succs[label].iter().rev() {
+            if !seen[succ] {
+                stack.push((succ, false));
+            }
+        }
+    }
+    postorder.reverse();
+    postorder
+}
@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+
+use crate::build_cfg::ast::{self, Label};
+use crate::build_cfg::dominators::reverse_postorder;
+use crate::pipeline::Stage;
+use crate::shared::ast::{Func, Op, Program, Triv, Value, Var};
+use crate::shared::names::Names;
+use crate::simplify_values::ast as input;
+
+// A call a function makes to itself in tail position becomes a jump back to the
+// block after its entry, with the arguments copied into the parameters on the way.
+// There is no stack to grow on the KENBAK, and the loop is cheaper than a call.
+pub struct Pass<'a> {
+    names: &'a mut Names,
+    func: Var,
+    params: Vec<Var>,
+    // Where a tail call to `func` jumps to, if it makes one.
+    header: Option<Label>,
+    blocks: Vec<Option<ast::Block>>,
+    current: Label,
+    stmts: Vec<ast::Stmt>,
+}
+
+impl Stage for Pass<'_> {
+    type Input = input::Exp;
+    type Output = ast::Cfg;
+    const NAME: &'static str = "build_cfg";
+
+    fn run(program: Program<input::Exp>, names: &mut Names) -> Program<ast::Cfg> {
+        Pass::run(program, names)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<input::Exp>, names: &mut Names) -> Program<ast::Cfg> {
+        let Program { globals, funcs } = program;
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let mut pass = Pass {
+                names,
+                func: name.clone(),
+                params: params.clone(),
+                header: None,
+                blocks: vec![None],
+                current: 0,
+                stmts: vec![],
+            };
+            // The entry block cannot be a loop header: a phi there would have no
+            // predecessor to take the parameters' first values from.
+            if pass.calls_itself(&body) {
+                let header = pass.new_block();
+                pass.terminate(ast::Terminator::Jump(header));
+                pass.switch_to(header);
+                pass.header = Some(header);
+            }
+            pass.tail(body);
+            output_funcs.insert(
+                name,
+                Func {
+                    params,
+                    body: pass.finish(),
+                },
+            );
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    fn make_tmp(&mut self) -> Var {
+        self.names.fresh("cfg.")
+    }
+
+    fn new_block(&mut self) -> Label {
+        self.blocks.push(None);
+        self.blocks.len() - 1
+    }
+
+    // Closes the block under construction; the next statement needs a `switch_to`.
+    fn terminate(&mut self, term: ast::Terminator) {
+        let stmts = std::mem::take(&mut self.stmts);
+        self.blocks[self.current] = Some(ast::Block { stmts, term });
+    }
+
+    fn switch_to(&mut self, label: Label) {
+        self.current = label;
+    }
+
+    fn push(&mut self, x: Var, rhs: ast::Rhs) {
+        self.stmts.push(ast::Stmt::Let(x, rhs));
+    }
+
+    fn is_self(&self, subject: &Triv, args: &[Triv]) -> bool {
+        let f = match subject {
+            Triv::Var(f) | Triv::Value(Value::Func(f)) => f,
+            _ => return false,
+        };
+        *f == self.func && args.len() == self.params.len()
+    }
+
+    fn calls_itself(&self, e: &input::Exp) -> bool {
+        match e {
+            input::Exp::Call(subject, args) => self.is_self(subject, args),
+            input::Exp::Seq(_, body) => self.calls_itself(body),
+            input::Exp::If(_, conseq, alt) => self.calls_itself(conseq) || self.calls_itself(alt),
+            _ => false,
+        }
+    }
+
+    fn tail(&mut self, e: input::Exp) {
+        match e {
+            input::Exp::Triv(t) => self.terminate(ast::Terminator::Return(t)),
+            input::Exp::Call(subject, args) if self.is_self(&subject, &args) => {
+                // Every argument is read before any parameter is overwritten.
+                let tmps = args
+                    .into_iter()
+                    .map(|arg| {
+                        let tmp = self.make_tmp();
+                        self.push(tmp.clone(), ast::Rhs::Triv(arg));
+                        tmp
+                    })
+                    .collect::<Vec<_>>();
+                for (x, tmp) in self.params.clone().into_iter().zip(tmps) {
+                    self.push(x, ast::Rhs::Triv(Triv::Var(tmp)));
+                }
+                let header = self.header.expect("calls_itself found this call");
+                self.terminate(ast::Terminator::Jump(header));
+            }
+            e @ (input::Exp::Call(_, _)
+            | input::Exp::Binop(_, _, _)
+            | input::Exp::Global(_)
+            | input::Exp::Index(_, _)) => {
+                let tmp = self.make_tmp();
+                self.value(Some(tmp.clone()), e);
+                self.terminate(ast::Terminator::Return(Triv::Var(tmp)));
+            }
+            input::Exp::Seq(stmts, body) => {
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+                self.tail(*body);
+            }
+            input::Exp::If(test, conseq, alt) => {
+                let conseq_label = self.new_block();
+                let alt_label = self.new_block();
+                self.pred(*test, conseq_label, alt_label);
+                self.switch_to(conseq_label);
+                self.tail(*conseq);
+                self.switch_to(alt_label);
+                self.tail(*alt);
+            }
+        }
+    }
+
+    // Evaluates `e` into `dest`, or just for effect when there is no destination.
+    fn value(&mut self, dest: Option<Var>, e: input::Exp) {
+        match e {
+            input::Exp::Call(subject, args) => {
+                let next = self.new_block();
+                self.terminate(ast::Terminator::Call {
+                    dest,
+                    subject,
+                    args,
+                    next,
+                });
+                self.switch_to(next);
+            }
+            input::Exp::Binop(x, op, rhs) => {
+                if let Some(dest) = dest {
+                    self.push(dest, ast::Rhs::Binop(x, op, rhs));
+                }
+            }
+            input::Exp::Triv(t) => {
+                if let Some(dest) = dest {
+                    self.push(dest, ast::Rhs::Triv(t));
+                }
+            }
+            input::Exp::Global(x) => {
+                if let Some(dest) = dest {
+                    self.push(dest, ast::Rhs::Global(x));
+                }
+            }
+            input::Exp::Index(a, i) => {
+                if let Some(dest) = dest {
+                    self.push(dest, ast::Rhs::Index(a, i));
+                }
+            }
+            input::Exp::Seq(stmts, body) => {
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+                self.value(dest, *body);
+            }
+            input::Exp::If(test, conseq, alt) => {
+                let conseq_label = self.new_block();
+                let alt_label = self.new_block();
+                let join = self.new_block();
+                self.pred(*test, conseq_label, alt_label);
+                self.switch_to(conseq_label);
+                self.value(dest.clone(), *conseq);
+                self.terminate(ast::Terminator::Jump(join));
+                self.switch_to(alt_label);
+                self.value(dest, *alt);
+                self.terminate(ast::Terminator::Jump(join));
+                self.switch_to(join);
+            }
+        }
+    }
+
+    fn stmt(&mut self, s: input::Stmt) {
+        match s {
+            input::Stmt::If(test, conseq, alt) => {
+                let conseq_label = self.new_block();
+                let alt_label = self.new_block();
+                let join = self.new_block();
+                self.pred(*test, conseq_label, alt_label);
+                self.switch_to(conseq_label);
+                for stmt in conseq {
+                    self.stmt(stmt);
+                }
+                self.terminate(ast::Terminator::Jump(join));
+                self.switch_to(alt_label);
+                for stmt in alt {
+                    self.stmt(stmt);
+                }
+                self.terminate(ast::Terminator::Jump(join));
+                self.switch_to(join);
+            }
+            input::Stmt::Seq(stmts) => {
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+            }
+            input::Stmt::Let(x, e) => self.value(Some(x), *e),
+            input::Stmt::SetGlobal(x, t) => self.stmts.push(ast::Stmt::SetGlobal(x, t)),
+            input::Stmt::SetIndex(a, i, t) => self.stmts.push(ast::Stmt::SetIndex(a, i, t)),
+            input::Stmt::Asm(asm) => self.stmts.push(ast::Stmt::Asm(asm)),
+            input::Stmt::Exp(e) => self.value(None, *e),
+        }
+    }
+
+    // Branches to `conseq` or `alt`; always terminates the current block.
+    fn pred(&mut self, p: input::Pred, conseq: Label, alt: Label) {
+        match p {
+            input::Pred::Call(subject, args) => {
+                let tmp = self.make_tmp();
+                self.value(Some(tmp.clone()), input::Exp::Call(subject, args));
+                self.truthy(tmp, conseq, alt);
+            }
+            input::Pred::Relop(x, op, rhs) => {
+                self.terminate(ast::Terminator::Branch(x, op, rhs, conseq, alt))
+            }
+            input::Pred::Triv(Triv::Value(v)) => {
+                let target = if v == Value::False { alt } else { conseq };
+                self.terminate(ast::Terminator::Jump(target));
+            }
+            input::Pred::Triv(Triv::Var(x)) => self.truthy(x, conseq, alt),
+            input::Pred::Triv(Triv::Return) => unreachable!(),
+            input::Pred::Seq(stmts, body) => {
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+                self.pred(*body, conseq, alt);
+            }
+            input::Pred::If(test, pconseq, palt) => {
+                let pconseq_label = self.new_block();
+                let palt_label = self.new_block();
+                self.pred(*test, pconseq_label, palt_label);
+                self.switch_to(pconseq_label);
+                self.pred(*pconseq, conseq, alt);
+                self.switch_to(palt_label);
+                self.pred(*palt, conseq, alt);
+            }
+            input::Pred::True => self.terminate(ast::Terminator::Jump(conseq)),
+            input::Pred::False => self.terminate(ast::Terminator::Jump(alt)),
+        }
+    }
+
+    // Scheme truthiness, as in normalize_context: anything but FALSE is true.
+    fn truthy(&mut self, x: Var, conseq: Label, alt: Label) {
+        self.terminate(ast::Terminator::Branch(
+            x,
+            Op::Neq,
+            Triv::Value(Value::False),
+            conseq,
+            alt,
+        ));
+    }
+
+    // Drops blocks nothing jumps to (e.g. the dead arm of a constant test) and
+    // renumbers the rest in reverse postorder from the entry.
+    fn finish(self) -> ast::Cfg {
+        let blocks = self
+            .blocks
+            .into_iter()
+            .map(|block| block.expect("every block is terminated"))
+            .collect::<Vec<_>>();
+        let succs = blocks
+            .iter()
+            .map(|block| block.term.successors())
+            .collect::<Vec<_>>();
+        let order = reverse_postorder(&succs);
+        let mut renumber = vec![None; blocks.len()];
+        for (new, old) in order.iter().enumerate() {
+            renumber[*old] = Some(new);
+        }
+        let relabel = |l: Label| renumber[l].expect("successor of a reachable block");
+        let mut slots = blocks.into_iter().map(Some).collect::<Vec<_>>();
+        let blocks = order
+            .iter()
+            .map(|old| {
+                let ast::Block { stmts, term } = slots[*old].take().unwrap();
+                let term = term.relabel(relabel);
+                ast::Block { stmts, term }
+            })
+            .collect();
+        ast::Cfg { blocks }
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::input::{self, Exp, Stmt};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Func, Global, Program, Value, Var};
+use crate::shared::names::Names;
+use crate::{hoist_arrays, rename_variables};
+
+// Lifts every lambda into a function of its own, `f.lambda.1` and so on for the ones
+// in f, skipping any name the program has already, and leaves its address in its
+// place. A lambda that never escapes, because it is called right where it is or
+// bound to a variable the rest of its block only calls, takes the variables it uses
+// from around it as extra arguments after its own, and each call passes them on.
+// There is no heap to put any other closure on, so what it captures goes in a
+// record of its own, the global array `f.lambda.1.env`: evaluating the lambda
+// stores their values there and the lifted function loads them back on entry. Two
+// closures made from one lambda would share the record, so `check` only accepts an
+// escaping lambda that captures where it can be evaluated at most once.
+pub struct Pass<'a> {
+    func: Var,
+    lifted: BTreeMap<Var, Func<Exp>>,
+    records: BTreeMap<Var, Global>,
+    // Variables bound to lambdas that never escape, with the address of each
+    // lambda's function and what it captured.
+    kept: BTreeMap<Var, (Exp, Vec<Var>)>,
+    names: &'a mut Names,
+}
+
+impl Stage for Pass<'_> {
+    type Input = Exp;
+    type Output = Exp;
+    const NAME: &'static str = "convert_closures";
+
+    fn run(program: Program<Exp>, names: &mut Names) -> Program<Exp> {
+        Pass::run(program, names)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<Exp>, names: &mut Names) -> Program<Exp> {
+        let Program { mut globals, funcs } = program;
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let mut pass = Pass {
+                func: name.clone(),
+                lifted: BTreeMap::new(),
+                records: BTreeMap::new(),
+                kept: BTreeMap::new(),
+                names,
+            };
+            let body = pass.exp(body, &params.iter().cloned().collect());
+            globals.append(&mut pass.records);
+            output_funcs.append(&mut pass.lifted);
+            output_funcs.insert(name, Func { params, body });
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    // `locals` are the variables in scope, the only ones a lambda can capture.
+    fn exp(&mut self, e: Exp, locals: &BTreeSet<Var>) -> Exp {
+        match e {
+            Exp::Call(subject, args) => {
+                let (subject, captured) = match *subject {
+                    Exp::Lambda(params, body) => self.lambda(params, *body, locals, false),
+                    Exp::Var(f) if self.kept.contains_key(&f) => self.kept[&f].clone(),
+                    subject => (self.exp(subject, locals), vec![]),
+                };
+                let args = args
+                    .into_iter()
+                    .map(|arg| self.exp(arg, locals))
+                    .chain(captured.into_iter().map(Exp::Var))
+                    .collect();
+                Exp::Call(Box::new(subject), args)
+            }
+            Exp::Seq(stmts, body) => {
+                let kept = kept_lambdas(&stmts, &body);
+                let mut locals = locals.clone();
+                let stmts = stmts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, stmt)| match stmt {
+                        Stmt::Exp(e) => Stmt::Exp(self.bexp(*e, &locals)),
+                        Stmt::Let(x, e) => {
+                            let e = match *e {
+                                Exp::Lambda(params, body) if kept.contains(&i) => {
+                                    let lifted = self.lambda(params, *body, &locals, false);
+                                    self.kept.insert(x.clone(), lifted.clone());
+                                    lifted.0
+                                }
+                                e => self.exp(e, &locals),
+                            };
+                            locals.insert(x.clone());
+                            Stmt::Let(x, Box::new(e))
+                        }
+                        Stmt::SetGlobal(x, e) => Stmt::SetGlobal(x, self.bexp(*e, &locals)),
+                        Stmt::SetIndex(a, i, e) => {
+                            Stmt::SetIndex(a, self.bexp(*i, &locals), self.bexp(*e, &locals))
+                        }
+                        s @ Stmt::Array(_, _) => s,
+                        Stmt::Asm(asm) => {
+                            locals.extend(asm.outputs.iter().cloned());
+                            Stmt::Asm(asm)
+                        }
+                    })
+                    .collect();
+                Exp::Seq(stmts, self.bexp(*body, &locals))
+            }
+            Exp::Binop(lhs, op, rhs) => {
+                Exp::Binop(self.bexp(*lhs, locals), op, self.bexp(*rhs, locals))
+            }
+            Exp::If(test, conseq, alt) => Exp::If(
+                self.bexp(*test, locals),
+                self.bexp(*conseq, locals),
+                self.bexp(*alt, locals),
+            ),
+            Exp::Index(a, i) => Exp::Index(a, self.bexp(*i, locals)),
+            Exp::Lambda(params, body) => self.lambda(params, *body, locals, true).0,
+            e @ (Exp::Value(_) | Exp::Var(_) | Exp::Global(_)) => e,
+        }
+    }
+
+    fn bexp(&mut self, e: Exp, locals: &BTreeSet<Var>) -> Box<Exp> {
+        Box::new(self.exp(e, locals))
+    }
+
+    // Lifts a lambda and gives what it turns into, along with the variables every
+    // call to it has to pass on if it does not `escape`.
+    fn lambda(
+        &mut self,
+        params: Vec<Var>,
+        body: Exp,
+        locals: &BTreeSet<Var>,
+        escapes: bool,
+    ) -> (Exp, Vec<Var>) {
+        let prefix = format!("{}.lambda.", self.func);
+        let (name, record) = if escapes {
+            loop {
+                let name = self.names.fresh(&prefix);
+                let record = format!("{}.env", name);
+                if self.names.claim(&record) {
+                    break (name, record);
+                }
+            }
+        } else {
+            (self.names.fresh(&prefix), String::new())
+        };
+
+        // Any other name is a function, or unbound wherever the lambda is.
+        let mut free = BTreeSet::new();
+        free_vars(&body, &params.iter().cloned().collect(), &mut free);
+        let captured = free
+            .into_iter()
+            .filter(|x| locals.contains(x))
+            .collect::<Vec<_>>();
+
+        // Lambdas inside this one capture from what it can see.
+        let inner = params.iter().chain(&captured).cloned().collect();
+        let body = self.exp(body, &inner);
+        let field = |i: usize| Box::new(Exp::Value(Value::Int(i as u8)));
+        let address = Exp::Value(Value::Func(name.clone()));
+        if !escapes {
+            let params = params.into_iter().chain(captured.iter().cloned()).collect();
+            self.lifted.insert(name, Func { params, body });
+            return (address, captured);
+        }
+        if captured.is_empty() {
+            self.lifted.insert(name, Func { params, body });
+            return (address, vec![]);
+        }
+        let loads = captured
+            .iter()
+            .enumerate()
+            .map(|(i, x)| Stmt::Let(x.clone(), Box::new(Exp::Index(record.clone(), field(i)))))
+            .collect();
+        let stores = captured
+            .iter()
+            .enumerate()
+            .map(|(i, x)| Stmt::SetIndex(record.clone(), field(i), Box::new(Exp::Var(x.clone()))))
+            .collect();
+        self.records.insert(record, Global::Array(captured.len()));
+        self.lifted.insert(
+            name,
+            Func {
+                params,
+                body: Exp::Seq(loads, Box::new(body)),
+            },
+        );
+        (Exp::Seq(stores, Box::new(address)), vec![])
+    }
+}
+
+// A lambda's record holds what it captured when it was last evaluated, so two
+// closures made from one lambda would share it. They cannot both exist if the
+// lambda is evaluated at most once in a run, so one that captures anything and
+// escapes, by being returned, stored, passed on or used from inside another
+// lambda, has to be in code that runs at most once: `main`, a function that is
+// never called by address and is called from exactly one place in code that runs
+// at most once, or the body of a lambda that is called right where it is. Lambdas
+// that never escape need no record and can capture anywhere.
+pub fn check(program: &Program<Exp>) -> Result<(), String> {
+    // With every binding named apart, a name is a function's exactly when no
+    // variable has it.
+    let mut names = Names::new(input::names(program));
+    let program = hoist_arrays::pass::Pass::run(program.clone(), &mut names);
+    let program = rename_variables::pass::Pass::run(program, &mut names);
+    let mut checker = Checker {
+        program: &program,
+        calls: vec![],
+        addressed: BTreeSet::new(),
+        capturing: vec![],
+    };
+    for global in program.globals.values() {
+        if let Global::Value(Value::Func(f)) = global {
+            checker.addressed.insert(f);
+        }
+    }
+    for (name, func) in &program.funcs {
+        checker.exp(name, &func.body, true);
+    }
+    let mut once = BTreeSet::new();
+    loop {
+        let more = program
+            .funcs
+            .keys()
+            .filter(|f| !once.contains(f) && !checker.addressed.contains(f))
+            .filter(|f| {
+                let mut sites = checker.calls.iter().filter(|(_, callee, _)| callee == f);
+                match (sites.next(), sites.next()) {
+                    (None, _) => *f == "main",
+                    (Some((caller, _, runs_once)), None) => *runs_once && once.contains(caller),
+                    (Some(_), Some(_)) => false,
+                }
+            })
+            .collect::<Vec<_>>();
+        if more.is_empty() {
+            break;
+        }
+        once.extend(more);
+    }
+    match checker
+        .capturing
+        .iter()
+        .find(|(func, runs_once)| !runs_once || !once.contains(func))
+    {
+        Some((func, _)) => Err(format!(
+            "{}: a lambda that captures variables and escapes can be evaluated more than \
+             once, and closures made from it would share what they captured",
+            func
+        )),
+        None => Ok(()),
+    }
+}
+
+struct Checker<'a> {
+    program: &'a Program<Exp>,
+    // Direct calls by caller and callee, and whether the call runs at most once
+    // each time its caller does.
+    calls: Vec<(&'a Var, &'a Var, bool)>,
+    addressed: BTreeSet<&'a Var>,
+    // The functions escaping lambdas that capture are in, and whether each is
+    // evaluated at most once each time its function runs.
+    capturing: Vec<(&'a Var, bool)>,
+}
+
+impl<'a> Checker<'a> {
+    // `func` is the function `e` is in, and `runs_once` whether `e` is evaluated at
+    // most once each time it runs.
+    fn exp(&mut self, func: &'a Var, e: &'a Exp, runs_once: bool) {
+        match e {
+            Exp::Call(subject, args) => {
+                match &**subject {
+                    Exp::Var(f) if self.program.funcs.contains_key(f) => {
+                        self.calls.push((func, f, runs_once))
+                    }
+                    Exp::Lambda(_, body) => self.exp(func, body, runs_once),
+                    subject => self.exp(func, subject, runs_once),
+                }
+                for arg in args {
+                    self.exp(func, arg, runs_once);
+                }
+            }
+            Exp::Seq(stmts, body) => {
+                let kept = kept_lambdas(stmts, body);
+                for (i, stmt) in stmts.iter().enumerate() {
+                    match stmt {
+                        // It may be called any number of times, but needs no record.
+                        Stmt::Let(_, e) if kept.contains(&i) => {
+                            if let Exp::Lambda(_, body) = &**e {
+                                self.exp(func, body, false);
+                            }
+                        }
+                        Stmt::Exp(e) | Stmt::Let(_, e) | Stmt::SetGlobal(_, e) => {
+                            self.exp(func, e, runs_once)
+                        }
+                        Stmt::SetIndex(_, i, e) => {
+                            self.exp(func, i, runs_once);
+                            self.exp(func, e, runs_once);
+                        }
+                        Stmt::Array(_, _) | Stmt::Asm(_) => (),
+                    }
+                }
+                self.exp(func, body, runs_once);
+            }
+            Exp::Binop(lhs, _, rhs) => {
+                self.exp(func, lhs, runs_once);
+                self.exp(func, rhs, runs_once);
+            }
+            Exp::If(test, conseq, alt) => {
+                self.exp(func, test, runs_once);
+                self.exp(func, conseq, runs_once);
+                self.exp(func, alt, runs_once);
+            }
+            Exp::Index(_, i) => self.exp(func, i, runs_once),
+            // A lambda kept to call later may be called any number of times.
+            Exp::Lambda(params, body) => {
+                let mut free = BTreeSet::new();
+                free_vars(body, &params.iter().cloned().collect(), &mut free);
+                if free.iter().any(|x| !self.program.funcs.contains_key(x)) {
+                    self.capturing.push((func, runs_once));
+                }
+                self.exp(func, body, false);
+            }
+            Exp::Value(Value::Func(f)) => {
+                self.addressed.insert(f);
+            }
+            Exp::Value(_) | Exp::Var(_) | Exp::Global(_) => (),
+        }
+    }
+}
+
+// The positions in a block of the `set!`s that bind a lambda the rest of the block
+// only ever calls. With every binding named apart, nothing else can see it.
+fn kept_lambdas(stmts: &[Stmt], body: &Exp) -> BTreeSet<usize> {
+    (0..stmts.len())
+        .filter(|&i| match &stmts[i] {
+            Stmt::Let(x, e) => {
+                matches!(**e, Exp::Lambda(_, _))
+                    && !stmts[i + 1..].iter().any(|stmt| stmt_escapes(x, stmt))
+                    && !escapes(x, body)
+            }
+            _ => false,
+        })
+        .collect()
+}
+
+// Whether `e` uses `x` other than to call it.
+fn escapes(x: &Var, e: &Exp) -> bool {
+    match e {
+        Exp::Call(subject, args) => {
+            !matches!(&**subject, Exp::Var(f) if f == x) && escapes(x, subject)
+                || args.iter().any(|arg| escapes(x, arg))
+        }
+        Exp::Seq(stmts, body) => stmts.iter().any(|stmt| stmt_escapes(x, stmt)) || escapes(x, body),
+        Exp::Binop(lhs, _, rhs) => escapes(x, lhs) || escapes(x, rhs),
+        Exp::If(test, conseq, alt) => escapes(x, test) || escapes(x, conseq) || escapes(x, alt),
+        Exp::Index(_, i) => escapes(x, i),
+        // Another lambda would have to capture it.
+        Exp::Lambda(_, _) => {
+            let mut free = BTreeSet::new();
+            free_vars(e, &BTreeSet::new(), &mut free);
+            free.contains(x)
+        }
+        Exp::Var(y) => y == x,
+        Exp::Value(_) | Exp::Global(_) => false,
+    }
+}
+
+fn stmt_escapes(x: &Var, stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Exp(e) | Stmt::Let(_, e) | Stmt::SetGlobal(_, e) => escapes(x, e),
+        Stmt::SetIndex(_, i, e) => escapes(x, i) || escapes(x, e),
+        Stmt::Array(_, _) => false,
+        Stmt::Asm(asm) => asm.inputs.contains(x),
+    }
+}
+
+// Adds the variables `e` reads that it does not bind itself.
+fn free_vars(e: &Exp, bound: &BTreeSet<Var>, out: &mut BTreeSet<Var>) {
+    match e {
+        Exp::Call(subject, args) => {
+            free_vars(subject, bound, out);
+            for arg in args {
+                free_vars(arg, bound, out);
+            }
+        }
+        Exp::Seq(stmts, body) => {
+            let mut bound = bound.clone();
+            for stmt in stmts {
+                match stmt {
+                    Stmt::Exp(e) | Stmt::SetGlobal(_, e) => free_vars(e, &bound, out),
+                    Stmt::Let(x, e) => {
+                        free_vars(e, &bound, out);
+                        bound.insert(x.clone());
+                    }
+                    Stmt::SetIndex(_, i, e) => {
+                        free_vars(i, &bound, out);
+                        free_vars(e, &bound, out);
+                    }
+                    Stmt::Array(_, _) => (),
+                    Stmt::Asm(asm) => {
+                        out.extend(asm.inputs.iter().filter(|x| !bound.contains(*x)).cloned());
+                        bound.extend(asm.outputs.iter().cloned());
+                    }
+                }
+            }
+            free_vars(body, &bound, out);
+        }
+        Exp::Binop(lhs, _, rhs) => {
+            free_vars(lhs, bound, out);
+            free_vars(rhs, bound, out);
+        }
+        Exp::If(test, conseq, alt) => {
+            free_vars(test, bound, out);
+            free_vars(conseq, bound, out);
+            free_vars(alt, bound, out);
+        }
+        Exp::Index(_, i) => free_vars(i, bound, out),
+        Exp::Lambda(params, body) => {
+            let mut bound = bound.clone();
+            bound.extend(params.iter().cloned());
+            free_vars(body, &bound, out);
+        }
+        Exp::Var(x) => {
+            if !bound.contains(x) {
+                out.insert(x.clone());
+            }
+        }
+        Exp::Value(_) | Exp::Global(_) => (),
+    }
+}
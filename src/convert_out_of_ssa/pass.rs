@@ -0,0 +1,109 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::build_cfg::ast::{self, Label, Rhs, Stmt, Terminator};
+use crate::convert_to_ssa::ast as input;
+use crate::pipeline::Stage;
+use crate::shared::ast::{Func, Program, Triv, Var};
+use crate::shared::names::Names;
+
+// Replaces each phi with copies at the end of its predecessors. A predecessor that
+// ends in a branch or a call gets a fresh block on that edge instead, so the copies
+// neither run on the other path nor happen before the call has set its result.
+pub struct Pass<'a> {
+    names: &'a mut Names,
+}
+
+impl Stage for Pass<'_> {
+    type Input = input::Cfg;
+    type Output = ast::Cfg;
+    const NAME: &'static str = "convert_out_of_ssa";
+
+    fn run(program: Program<input::Cfg>, names: &mut Names) -> Program<ast::Cfg> {
+        Pass::run(program, names)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<input::Cfg>, names: &mut Names) -> Program<ast::Cfg> {
+        let Program { globals, funcs } = program;
+        let mut pass = Pass { names };
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            output_funcs.insert(
+                name,
+                Func {
+                    params,
+                    body: pass.cfg(body),
+                },
+            );
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    fn make_tmp(&mut self) -> Var {
+        self.names.fresh("phi.")
+    }
+
+    fn cfg(&mut self, cfg: input::Cfg) -> ast::Cfg {
+        let mut copies: BTreeMap<(Label, Label), Vec<(Var, Triv)>> = BTreeMap::new();
+        let mut blocks = vec![];
+        for (label, block) in cfg.blocks.into_iter().enumerate() {
+            for phi in block.phis {
+                for (pred, t) in phi.args {
+                    copies
+                        .entry((pred, label))
+                        .or_default()
+                        .push((phi.dest.clone(), t));
+                }
+            }
+            blocks.push(ast::Block {
+                stmts: block.stmts,
+                term: block.term,
+            });
+        }
+
+        for ((pred, succ), edge_copies) in copies {
+            let stmts = self.sequentialize(edge_copies);
+            if let Terminator::Jump(_) = blocks[pred].term {
+                blocks[pred].stmts.extend(stmts);
+                continue;
+            }
+            let split = blocks.len();
+            blocks.push(ast::Block {
+                stmts,
+                term: Terminator::Jump(succ),
+            });
+            let term = blocks[pred].term.clone();
+            blocks[pred].term = term.relabel(|l| if l == succ { split } else { l });
+        }
+        ast::Cfg { blocks }
+    }
+
+    // The copies on an edge happen all at once, so if one overwrites a variable another
+    // still has to read, route every copy through a temporary first.
+    fn sequentialize(&mut self, copies: Vec<(Var, Triv)>) -> Vec<Stmt> {
+        let dests = copies.iter().map(|(x, _)| x).collect::<BTreeSet<_>>();
+        let conflict = copies
+            .iter()
+            .any(|(x, t)| matches!(t, Triv::Var(y) if y != x && dests.contains(y)));
+        if !conflict {
+            return copies
+                .into_iter()
+                .filter(|(x, t)| *t != Triv::Var(x.clone()))
+                .map(|(x, t)| Stmt::Let(x, Rhs::Triv(t)))
+                .collect();
+        }
+        let mut stmts = vec![];
+        let mut moves = vec![];
+        for (x, t) in copies {
+            let tmp = self.make_tmp();
+            stmts.push(Stmt::Let(tmp.clone(), Rhs::Triv(t)));
+            moves.push(Stmt::Let(x, Rhs::Triv(Triv::Var(tmp))));
+        }
+        stmts.extend(moves);
+        stmts
+    }
+}
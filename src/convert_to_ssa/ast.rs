@@ -0,0 +1,300 @@
+use std::collections::BTreeSet;
+
+use pretty::{Doc, RcDoc};
+
+use crate::build_cfg::ast::{label_doc, parse_label};
+pub use crate::build_cfg::ast::{Label, Rhs, Stmt, Terminator};
+use crate::build_cfg::dominators::{predecessors, reverse_postorder};
+use crate::shared::{
+    ast::{parse_var, Triv, Var},
+    read::{expected, Sexp},
+    Parse, ToDoc, Verify,
+};
+
+// The same blocks as build_cfg, except every variable assigned in the function has
+// exactly one definition and joins go through phis at the top of a block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub phis: Vec<Phi>,
+    pub stmts: Vec<Stmt>,
+    pub term: Terminator,
+}
+
+// `dest` takes the argument paired with whichever predecessor control came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phi {
+    pub dest: Var,
+    pub args: Vec<(Label, Triv)>,
+}
+
+impl Cfg {
+    pub fn successors(&self) -> Vec<Vec<Label>> {
+        self.blocks
+            .iter()
+            .map(|block| block.term.successors())
+            .collect()
+    }
+
+    // Drops blocks that can no longer be reached, along with the phi arguments that
+    // flowed in from them, and renumbers the rest in reverse postorder.
+    pub fn prune_unreachable(self) -> Cfg {
+        let order = reverse_postorder(&self.successors());
+        let mut renumber = vec![None; self.blocks.len()];
+        for (new, old) in order.iter().enumerate() {
+            renumber[*old] = Some(new);
+        }
+        let relabel = |l: Label| renumber[l].expect("successor of a reachable block");
+        let mut slots = self.blocks.into_iter().map(Some).collect::<Vec<_>>();
+        let blocks = order
+            .iter()
+            .map(|old| {
+                let Block { phis, stmts, term } = slots[*old].take().unwrap();
+                let phis = phis
+                    .into_iter()
+                    .map(|Phi { dest, args }| Phi {
+                        dest,
+                        args: args
+                            .into_iter()
+                            .filter_map(|(l, t)| renumber[l].map(|l| (l, t)))
+                            .collect(),
+                    })
+                    .collect();
+                Block {
+                    phis,
+                    stmts,
+                    term: term.relabel(relabel),
+                }
+            })
+            .collect();
+        Cfg { blocks }
+    }
+}
+
+impl Cfg {
+    // Drops phis and lets nothing reads, including loads of globals and arrays. In SSA a
+    // definition with no uses is dead everywhere, so this only has to repeat until
+    // removals stop exposing more.
+    pub fn remove_dead_definitions(mut self) -> Cfg {
+        loop {
+            let mut used = BTreeSet::new();
+            for block in &self.blocks {
+                for phi in &block.phis {
+                    for (_, t) in &phi.args {
+                        triv_uses(&mut used, t);
+                    }
+                }
+                for stmt in &block.stmts {
+                    match stmt {
+                        Stmt::Let(_, Rhs::Triv(t)) | Stmt::SetGlobal(_, t) => {
+                            triv_uses(&mut used, t)
+                        }
+                        Stmt::Let(_, Rhs::Binop(x, _, t)) => {
+                            used.insert(x.clone());
+                            triv_uses(&mut used, t);
+                        }
+                        Stmt::Let(_, Rhs::Index(_, i)) => triv_uses(&mut used, i),
+                        Stmt::SetIndex(_, i, t) => {
+                            triv_uses(&mut used, i);
+                            triv_uses(&mut used, t);
+                        }
+                        Stmt::Let(_, Rhs::Global(_)) => (),
+                        Stmt::Asm(asm) => used.extend(asm.inputs.iter().cloned()),
+                    }
+                }
+                match &block.term {
+                    Terminator::Jump(_) => (),
+                    Terminator::Branch(x, _, t, _, _) => {
+                        used.insert(x.clone());
+                        triv_uses(&mut used, t);
+                    }
+                    Terminator::Call { subject, args, .. } => {
+                        triv_uses(&mut used, subject);
+                        for arg in args {
+                            triv_uses(&mut used, arg);
+                        }
+                    }
+                    Terminator::Return(t) => triv_uses(&mut used, t),
+                }
+            }
+            let mut changed = false;
+            for block in &mut self.blocks {
+                let before = block.phis.len() + block.stmts.len();
+                block.phis.retain(|phi| used.contains(&phi.dest));
+                block.stmts.retain(|stmt| match stmt {
+                    Stmt::Let(x, _) => used.contains(x),
+                    // Whatever else an asm block does is out of sight.
+                    Stmt::SetGlobal(_, _) | Stmt::SetIndex(_, _, _) | Stmt::Asm(_) => true,
+                });
+                changed |= block.phis.len() + block.stmts.len() != before;
+            }
+            if !changed {
+                return self;
+            }
+        }
+    }
+}
+
+// Every variable is defined once, and each phi has exactly one argument for each
+// predecessor of its block.
+impl Verify for Cfg {
+    fn verify(&self) -> Result<(), String> {
+        if self.blocks.is_empty() {
+            return Err("no entry block".to_string());
+        }
+        for (label, block) in self.blocks.iter().enumerate() {
+            block.term.verify(label, self.blocks.len())?;
+        }
+        let preds = predecessors(&self.successors());
+        let mut defined = BTreeSet::new();
+        for (label, block) in self.blocks.iter().enumerate() {
+            let expected = preds[label].iter().collect::<BTreeSet<_>>();
+            for phi in &block.phis {
+                let incoming = phi.args.iter().map(|(l, _)| l).collect::<BTreeSet<_>>();
+                if incoming != expected || phi.args.len() != expected.len() {
+                    return Err(format!(
+                        "phi for {} in b{} has arguments from {:?}, predecessors are {:?}",
+                        phi.dest, label, incoming, expected
+                    ));
+                }
+            }
+            let dests = block
+                .phis
+                .iter()
+                .map(|phi| &phi.dest)
+                .chain(block.stmts.iter().flat_map(|stmt| match stmt {
+                    Stmt::Let(x, _) => std::slice::from_ref(x),
+                    Stmt::Asm(asm) => &asm.outputs[..],
+                    Stmt::SetGlobal(_, _) | Stmt::SetIndex(_, _, _) => &[],
+                }))
+                .chain(match &block.term {
+                    Terminator::Call { dest: Some(x), .. } => Some(x),
+                    _ => None,
+                });
+            for x in dests {
+                if !defined.insert(x) {
+                    return Err(format!("{} is defined more than once", x));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn triv_uses(used: &mut BTreeSet<Var>, t: &Triv) {
+    if let Triv::Var(x) = t {
+        used.insert(x.clone());
+    }
+}
+
+impl ToDoc for Cfg {
+    fn to_doc(&self) -> RcDoc<'_> {
+        let blocks = self.blocks.iter().enumerate().map(|(label, block)| {
+            RcDoc::text("(")
+                .append(label_doc(label))
+                .append(RcDoc::line().append(block.to_doc()).nest(2))
+                .append(RcDoc::text(")"))
+                .group()
+        });
+        RcDoc::text("(ssa")
+            .append(
+                RcDoc::line()
+                    .append(RcDoc::intersperse(blocks, Doc::line()))
+                    .nest(2),
+            )
+            .append(RcDoc::text(")"))
+    }
+}
+
+impl ToDoc for Block {
+    fn to_doc(&self) -> RcDoc<'_> {
+        let lines = self
+            .phis
+            .iter()
+            .map(|phi| phi.to_doc())
+            .chain(self.stmts.iter().map(|stmt| stmt.to_doc()))
+            .chain([self.term.to_doc()]);
+        RcDoc::intersperse(lines, Doc::line())
+    }
+}
+
+impl ToDoc for Phi {
+    fn to_doc(&self) -> RcDoc<'_> {
+        let args = self.args.iter().map(|(l, t)| {
+            RcDoc::text("(")
+                .append(label_doc(*l))
+                .append(RcDoc::space())
+                .append(t.to_doc())
+                .append(RcDoc::text(")"))
+        });
+        let phi = RcDoc::text("(phi ")
+            .append(RcDoc::intersperse(args, Doc::line()).nest(2).group())
+            .append(RcDoc::text(")"));
+        RcDoc::text("(set! ")
+            .append(
+                RcDoc::intersperse([RcDoc::text(&self.dest), phi], Doc::line())
+                    .nest(2)
+                    .group(),
+            )
+            .append(RcDoc::text(")"))
+    }
+}
+
+impl Parse for Cfg {
+    fn parse(sexp: &Sexp) -> Result<Cfg, String> {
+        let blocks = match sexp.form() {
+            Some(("ssa", blocks)) => blocks,
+            _ => return expected("(ssa block ...)", sexp),
+        };
+        let blocks = blocks
+            .iter()
+            .enumerate()
+            .map(|(label, block)| match block.list() {
+                Some([l, lines @ .., term]) if parse_label(l) == Ok(label) => {
+                    let mut phis = vec![];
+                    let mut stmts = vec![];
+                    for line in lines {
+                        match parse_phi(line)? {
+                            Some(phi) if stmts.is_empty() => phis.push(phi),
+                            Some(_) => return expected("phis before other statements", line),
+                            None => stmts.push(Stmt::parse(line)?),
+                        }
+                    }
+                    Ok(Block {
+                        phis,
+                        stmts,
+                        term: Terminator::parse(term)?,
+                    })
+                }
+                _ => expected(&format!("(b{} stmt ... terminator)", label), block),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Cfg { blocks })
+    }
+}
+
+// `(set! x (phi (b1 t) ...))`, or `None` for any other statement.
+fn parse_phi(sexp: &Sexp) -> Result<Option<Phi>, String> {
+    let (dest, args) = match sexp.form() {
+        Some(("set!", [dest, rhs])) => match rhs.form() {
+            Some(("phi", args)) => (dest, args),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let args = args
+        .iter()
+        .map(|arg| match arg.list() {
+            Some([l, t]) => Ok((parse_label(l)?, Triv::parse(t)?)),
+            _ => expected("(label value)", arg),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Some(Phi {
+        dest: parse_var(dest)?,
+        args,
+    }))
+}
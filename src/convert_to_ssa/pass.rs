@@ -0,0 +1,236 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::build_cfg::ast as input;
+use crate::build_cfg::dominators::Dominators;
+use crate::convert_to_ssa::ast::{self, Label, Rhs, Stmt, Terminator};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Asm, Func, Program, Triv, Var};
+use crate::shared::names::Names;
+
+// Variables are only renamed if the function assigns them. Anything else (parameters,
+// other functions) keeps its name, and so does the value a variable has on entry,
+// which is what an assigned parameter reads before its first definition. Globals
+// and arrays are memory rather than variables, so loads and stores keep theirs too.
+pub struct Pass<'a> {
+    names: &'a mut Names,
+    stacks: BTreeMap<Var, Vec<Var>>,
+    // The source variable each phi was placed for, parallel to each block's phis.
+    phi_vars: Vec<Vec<Var>>,
+}
+
+impl Stage for Pass<'_> {
+    type Input = input::Cfg;
+    type Output = ast::Cfg;
+    const NAME: &'static str = "convert_to_ssa";
+
+    fn run(program: Program<input::Cfg>, names: &mut Names) -> Program<ast::Cfg> {
+        Pass::run(program, names)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<input::Cfg>, names: &mut Names) -> Program<ast::Cfg> {
+        let Program { globals, funcs } = program;
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let mut pass = Pass {
+                names,
+                stacks: BTreeMap::new(),
+                phi_vars: vec![],
+            };
+            output_funcs.insert(
+                name,
+                Func {
+                    params,
+                    body: pass.cfg(body),
+                },
+            );
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    fn cfg(&mut self, cfg: input::Cfg) -> ast::Cfg {
+        let succs = cfg
+            .blocks
+            .iter()
+            .map(|block| block.term.successors())
+            .collect::<Vec<_>>();
+        let doms = Dominators::new(&succs);
+
+        let mut defsites: BTreeMap<Var, BTreeSet<Label>> = BTreeMap::new();
+        for (label, block) in cfg.blocks.iter().enumerate() {
+            for stmt in &block.stmts {
+                match stmt {
+                    Stmt::Let(x, _) => {
+                        defsites.entry(x.clone()).or_default().insert(label);
+                    }
+                    Stmt::Asm(asm) => {
+                        for x in &asm.outputs {
+                            defsites.entry(x.clone()).or_default().insert(label);
+                        }
+                    }
+                    Stmt::SetGlobal(_, _) | Stmt::SetIndex(_, _, _) => (),
+                }
+            }
+            if let Terminator::Call { dest: Some(x), .. } = &block.term {
+                defsites.entry(x.clone()).or_default().insert(label);
+            }
+        }
+
+        self.phi_vars = vec![vec![]; cfg.blocks.len()];
+        for (x, sites) in &defsites {
+            let mut has_phi = BTreeSet::new();
+            let mut worklist = sites.iter().copied().collect::<Vec<_>>();
+            while let Some(label) = worklist.pop() {
+                for &frontier in &doms.frontiers[label] {
+                    if has_phi.insert(frontier) {
+                        self.phi_vars[frontier].push(x.clone());
+                        if !sites.contains(&frontier) {
+                            worklist.push(frontier);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut blocks = cfg
+            .blocks
+            .into_iter()
+            .enumerate()
+            .map(|(label, block)| {
+                let phis = self.phi_vars[label]
+                    .iter()
+                    .map(|x| ast::Phi {
+                        dest: x.clone(),
+                        args: vec![],
+                    })
+                    .collect();
+                ast::Block {
+                    phis,
+                    stmts: block.stmts,
+                    term: block.term,
+                }
+            })
+            .collect::<Vec<_>>();
+        self.rename(0, &mut blocks, &doms);
+        // Phis go in wherever a definition's frontier reaches, live or not.
+        ast::Cfg { blocks }.remove_dead_definitions()
+    }
+
+    fn fresh(&mut self, x: &Var) -> Var {
+        let new = self.names.fresh(&format!("{}#", x));
+        self.stacks.entry(x.clone()).or_default().push(new.clone());
+        new
+    }
+
+    fn current(&self, x: &Var) -> Var {
+        self.stacks
+            .get(x)
+            .and_then(|stack| stack.last())
+            .cloned()
+            .unwrap_or_else(|| x.clone())
+    }
+
+    fn triv(&self, t: Triv) -> Triv {
+        match t {
+            Triv::Var(x) => Triv::Var(self.current(&x)),
+            t @ (Triv::Value(_) | Triv::Return) => t,
+        }
+    }
+
+    // Walks the dominator tree so every use sees the definition that reaches it.
+    fn rename(&mut self, label: Label, blocks: &mut [ast::Block], doms: &Dominators) {
+        let mut defined = vec![];
+
+        for (i, x) in self.phi_vars[label].clone().iter().enumerate() {
+            blocks[label].phis[i].dest = self.fresh(x);
+            defined.push(x.clone());
+        }
+
+        let stmts = std::mem::take(&mut blocks[label].stmts);
+        blocks[label].stmts = stmts
+            .into_iter()
+            .map(|stmt| match stmt {
+                Stmt::Let(x, rhs) => {
+                    let rhs = match rhs {
+                        Rhs::Triv(t) => Rhs::Triv(self.triv(t)),
+                        Rhs::Binop(y, op, t) => Rhs::Binop(self.current(&y), op, self.triv(t)),
+                        rhs @ Rhs::Global(_) => rhs,
+                        Rhs::Index(a, i) => Rhs::Index(a, self.triv(i)),
+                    };
+                    defined.push(x.clone());
+                    Stmt::Let(self.fresh(&x), rhs)
+                }
+                Stmt::SetGlobal(x, t) => Stmt::SetGlobal(x, self.triv(t)),
+                Stmt::SetIndex(a, i, t) => Stmt::SetIndex(a, self.triv(i), self.triv(t)),
+                Stmt::Asm(Asm {
+                    outputs,
+                    inputs,
+                    body,
+                }) => {
+                    let inputs = inputs.iter().map(|x| self.current(x)).collect();
+                    let outputs = outputs
+                        .iter()
+                        .map(|x| {
+                            defined.push(x.clone());
+                            self.fresh(x)
+                        })
+                        .collect();
+                    Stmt::Asm(Asm {
+                        outputs,
+                        inputs,
+                        body,
+                    })
+                }
+            })
+            .collect();
+
+        let term = match blocks[label].term.clone() {
+            term @ Terminator::Jump(_) => term,
+            Terminator::Branch(x, op, rhs, conseq, alt) => {
+                Terminator::Branch(self.current(&x), op, self.triv(rhs), conseq, alt)
+            }
+            Terminator::Call {
+                dest,
+                subject,
+                args,
+                next,
+            } => {
+                let subject = self.triv(subject);
+                let args = args.into_iter().map(|arg| self.triv(arg)).collect();
+                let dest = dest.map(|x| {
+                    defined.push(x.clone());
+                    self.fresh(&x)
+                });
+                Terminator::Call {
+                    dest,
+                    subject,
+                    args,
+                    next,
+                }
+            }
+            Terminator::Return(t) => Terminator::Return(self.triv(t)),
+        };
+        let mut succs = term.successors();
+        succs.dedup();
+        blocks[label].term = term;
+
+        for succ in succs {
+            for (i, x) in self.phi_vars[succ].iter().enumerate() {
+                let arg = Triv::Var(self.current(x));
+                blocks[succ].phis[i].args.push((label, arg));
+            }
+        }
+
+        for &child in &doms.children[label] {
+            self.rename(child, blocks, doms);
+        }
+
+        for x in defined {
+            self.stacks.get_mut(&x).and_then(|stack| stack.pop());
+        }
+    }
+}
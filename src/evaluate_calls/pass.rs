@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::inline_functions::pass::called_funcs;
+use crate::input::{Exp, Stmt};
+use crate::interpret::{self, Error};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Func, Program, Value, Var};
+use crate::shared::names::Names;
+use crate::shared::ToDoc;
+
+// Enough for something like (fib 15); a call that needs more is left for run time.
+pub const DEFAULT_BUDGET: usize = 100_000;
+
+// Runs calls that mention no local variables at compile time and puts their result
+// in their place. Nothing in the language reads the switches yet, so such a call
+// computes the same value on every run unless it touches a global or an array;
+// those get stuck in the interpreter and are left alone.
+pub struct Pass<'a> {
+    program: &'a Program<Exp>,
+    budget: usize,
+    // The function being rewritten, for warnings.
+    name: Var,
+}
+
+impl Stage for Pass<'_> {
+    type Input = Exp;
+    type Output = Exp;
+    const NAME: &'static str = "evaluate_calls";
+
+    fn run(program: Program<Exp>, _: &mut Names) -> Program<Exp> {
+        Pass::run(program, DEFAULT_BUDGET)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<Exp>, budget: usize) -> Program<Exp> {
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in &program.funcs {
+            let pass = Pass {
+                program: &program,
+                budget,
+                name: name.clone(),
+            };
+            let bound = params.iter().cloned().collect();
+            output_funcs.insert(
+                name.clone(),
+                Func {
+                    params: params.clone(),
+                    body: pass.exp(body.clone(), &bound),
+                },
+            );
+        }
+        // As when inlining, a function whose callers were all evaluated is dead, but
+        // one nobody called to begin with is an entry point.
+        let called_before = called_funcs(&program.globals, &program.funcs);
+        let called_after = called_funcs(&program.globals, &output_funcs);
+        output_funcs.retain(|name, _| !called_before.contains(name) || called_after.contains(name));
+        Program {
+            globals: program.globals.clone(),
+            funcs: output_funcs,
+        }
+    }
+
+    fn exp(&self, e: Exp, bound: &BTreeSet<Var>) -> Exp {
+        match e {
+            Exp::Call(subject, args) => {
+                let call = Exp::Call(subject.clone(), args.clone());
+                if let Some(v) = self.evaluate(&call, bound) {
+                    return Exp::Value(v);
+                }
+                Exp::Call(
+                    Box::new(self.exp(*subject, bound)),
+                    args.into_iter().map(|arg| self.exp(arg, bound)).collect(),
+                )
+            }
+            Exp::Seq(stmts, body) => {
+                let mut bound = bound.clone();
+                let stmts = stmts
+                    .into_iter()
+                    .map(|stmt| match stmt {
+                        Stmt::Exp(e) => Stmt::Exp(Box::new(self.exp(*e, &bound))),
+                        Stmt::Let(x, e) => {
+                            let e = self.exp(*e, &bound);
+                            bound.insert(x.clone());
+                            Stmt::Let(x, Box::new(e))
+                        }
+                        Stmt::SetGlobal(x, e) => Stmt::SetGlobal(x, Box::new(self.exp(*e, &bound))),
+                        Stmt::SetIndex(a, i, e) => Stmt::SetIndex(
+                            a,
+                            Box::new(self.exp(*i, &bound)),
+                            Box::new(self.exp(*e, &bound)),
+                        ),
+                        s @ Stmt::Array(_, _) => s,
+                        Stmt::Asm(asm) => {
+                            bound.extend(asm.outputs.iter().cloned());
+                            Stmt::Asm(asm)
+                        }
+                    })
+                    .collect();
+                Exp::Seq(stmts, Box::new(self.exp(*body, &bound)))
+            }
+            Exp::Binop(lhs, op, rhs) => Exp::Binop(
+                Box::new(self.exp(*lhs, bound)),
+                op,
+                Box::new(self.exp(*rhs, bound)),
+            ),
+            Exp::If(test, conseq, alt) => Exp::If(
+                Box::new(self.exp(*test, bound)),
+                Box::new(self.exp(*conseq, bound)),
+                Box::new(self.exp(*alt, bound)),
+            ),
+            Exp::Index(a, i) => Exp::Index(a, Box::new(self.exp(*i, bound))),
+            e @ (Exp::Value(_) | Exp::Var(_) | Exp::Global(_) | Exp::Lambda(_, _)) => e,
+        }
+    }
+
+    fn evaluate(&self, call: &Exp, bound: &BTreeSet<Var>) -> Option<Value> {
+        if mentions_any(call, bound) {
+            return None;
+        }
+        match interpret::input::eval(self.program, call, self.budget) {
+            Ok(v) => Some(v),
+            Err(err @ (Error::OutOfSteps | Error::TooDeep)) => {
+                self.warn(call, err);
+                None
+            }
+            // Left to go wrong at run time, as written.
+            Err(Error::Stuck(_)) => None,
+        }
+    }
+
+    fn warn(&self, call: &Exp, err: Error) {
+        let mut text = Vec::new();
+        let _ = call.to_doc().render(usize::MAX, &mut text);
+        let reason = match err {
+            Error::TooDeep => "nested too deeply".to_string(),
+            _ => format!("took more than {} steps", self.budget),
+        };
+        eprintln!(
+            "warning: {}: left {} to run time: {}",
+            self.name,
+            String::from_utf8_lossy(&text),
+            reason
+        );
+    }
+}
+
+fn mentions_any(e: &Exp, vars: &BTreeSet<Var>) -> bool {
+    match e {
+        Exp::Call(subject, args) => {
+            mentions_any(subject, vars) || args.iter().any(|arg| mentions_any(arg, vars))
+        }
+        Exp::Seq(stmts, body) => {
+            stmts.iter().any(|stmt| match stmt {
+                Stmt::Exp(e) | Stmt::Let(_, e) | Stmt::SetGlobal(_, e) => mentions_any(e, vars),
+                Stmt::SetIndex(_, i, e) => mentions_any(i, vars) || mentions_any(e, vars),
+                Stmt::Array(_, _) => false,
+                Stmt::Asm(asm) => asm.inputs.iter().any(|x| vars.contains(x)),
+            }) || mentions_any(body, vars)
+        }
+        Exp::Binop(lhs, _, rhs) => mentions_any(lhs, vars) || mentions_any(rhs, vars),
+        Exp::If(test, conseq, alt) => {
+            mentions_any(test, vars) || mentions_any(conseq, vars) || mentions_any(alt, vars)
+        }
+        Exp::Index(_, i) => mentions_any(i, vars),
+        Exp::Lambda(_, body) => mentions_any(body, vars),
+        Exp::Value(_) | Exp::Global(_) => false,
+        Exp::Var(x) => vars.contains(x),
+    }
+}
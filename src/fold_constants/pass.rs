@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::normalize_context::ast;
+use crate::pipeline::Stage;
+use crate::shared::ast::{Func, Program, Value, Var};
+use crate::shared::names::Names;
+
+pub struct Pass {
+    env: BTreeMap<Var, Value>,
+}
+
+impl Stage for Pass {
+    type Input = ast::Exp;
+    type Output = ast::Exp;
+    const NAME: &'static str = "fold_constants";
+
+    fn run(program: Program<ast::Exp>, _: &mut Names) -> Program<ast::Exp> {
+        Pass::run(program)
+    }
+}
+
+impl Pass {
+    pub fn run(program: Program<ast::Exp>) -> Program<ast::Exp> {
+        let Program { globals, funcs } = program;
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let mut pass = Pass {
+                env: BTreeMap::new(),
+            };
+            output_funcs.insert(
+                name,
+                Func {
+                    params,
+                    body: pass.exp(body),
+                },
+            );
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    fn exp(&mut self, e: ast::Exp) -> ast::Exp {
+        match e {
+            ast::Exp::Call(subject, args) => ast::Exp::Call(
+                self.bexp(*subject),
+                args.into_iter().map(|arg| self.exp(arg)).collect(),
+            ),
+            ast::Exp::Seq(stmts, body) => {
+                let saved = self.env.clone();
+                let stmts = self.stmts(stmts);
+                let body = self.exp(*body);
+                self.leave_scope(saved, &stmts);
+                make_block(stmts, body)
+            }
+            ast::Exp::If(test, conseq, alt) => match self.pred(*test) {
+                ast::Pred::True => self.exp(*conseq),
+                ast::Pred::False => self.exp(*alt),
+                test => {
+                    let conseq = self.branch(|pass| pass.bexp(*conseq));
+                    let alt = self.branch(|pass| pass.bexp(*alt));
+                    ast::Exp::If(Box::new(test), conseq, alt)
+                }
+            },
+            ast::Exp::Binop(lhs, op, rhs) => {
+                let lhs = self.exp(*lhs);
+                let rhs = self.exp(*rhs);
+                let folded = match (&lhs, &rhs) {
+                    (ast::Exp::Value(v), ast::Exp::Value(w)) => op.apply(v, w),
+                    _ => None,
+                };
+                match folded {
+                    Some(v) => ast::Exp::Value(v),
+                    None => ast::Exp::Binop(Box::new(lhs), op, Box::new(rhs)),
+                }
+            }
+            // Any call could have stored to it since, so a global is never known.
+            e @ (ast::Exp::Value(_) | ast::Exp::Global(_)) => e,
+            // The same goes for an array element, though its index can still fold.
+            ast::Exp::Index(a, i) => ast::Exp::Index(a, self.bexp(*i)),
+            ast::Exp::Var(x) => match self.env.get(&x) {
+                Some(v) => ast::Exp::Value(v.clone()),
+                None => ast::Exp::Var(x),
+            },
+        }
+    }
+
+    fn bexp(&mut self, e: ast::Exp) -> Box<ast::Exp> {
+        Box::new(self.exp(e))
+    }
+
+    fn pred(&mut self, p: ast::Pred) -> ast::Pred {
+        match p {
+            ast::Pred::Call(subject, args) => ast::Pred::Call(
+                self.bexp(*subject),
+                args.into_iter().map(|arg| self.exp(arg)).collect(),
+            ),
+            ast::Pred::Relop(lhs, op, rhs) => {
+                let lhs = self.exp(*lhs);
+                let rhs = self.exp(*rhs);
+                let holds = match (&lhs, &rhs) {
+                    (ast::Exp::Value(v), ast::Exp::Value(w)) => op.holds(v, w),
+                    _ => None,
+                };
+                match holds {
+                    Some(true) => ast::Pred::True,
+                    Some(false) => ast::Pred::False,
+                    None => ast::Pred::Relop(Box::new(lhs), op, Box::new(rhs)),
+                }
+            }
+            ast::Pred::Seq(stmts, body) => {
+                let saved = self.env.clone();
+                let stmts = self.stmts(stmts);
+                let body = self.pred(*body);
+                self.leave_scope(saved, &stmts);
+                make_pred_block(stmts, body)
+            }
+            ast::Pred::If(test, conseq, alt) => match self.pred(*test) {
+                ast::Pred::True => self.pred(*conseq),
+                ast::Pred::False => self.pred(*alt),
+                test => {
+                    let conseq = self.branch(|pass| Box::new(pass.pred(*conseq)));
+                    let alt = self.branch(|pass| Box::new(pass.pred(*alt)));
+                    ast::Pred::If(Box::new(test), conseq, alt)
+                }
+            },
+            ast::Pred::True => ast::Pred::True,
+            ast::Pred::False => ast::Pred::False,
+        }
+    }
+
+    fn stmts(&mut self, ss: Vec<ast::Stmt>) -> Vec<ast::Stmt> {
+        let mut block = vec![];
+        for s in ss {
+            self.stmt(&mut block, s);
+        }
+        block
+    }
+
+    // fully for effect; pushes the surviving statements onto the block
+    fn stmt(&mut self, block: &mut Vec<ast::Stmt>, s: ast::Stmt) {
+        match s {
+            ast::Stmt::If(test, conseq, alt) => match self.pred(*test) {
+                // The live branch runs unconditionally, so its bindings stay in scope.
+                ast::Pred::True => {
+                    for s in conseq {
+                        self.stmt(block, s);
+                    }
+                }
+                ast::Pred::False => {
+                    for s in alt {
+                        self.stmt(block, s);
+                    }
+                }
+                test => {
+                    let conseq = self.branch(|pass| pass.stmts(conseq));
+                    let alt = self.branch(|pass| pass.stmts(alt));
+                    for x in bound_vars(&conseq).into_iter().chain(bound_vars(&alt)) {
+                        self.env.remove(&x);
+                    }
+                    block.push(ast::Stmt::If(Box::new(test), conseq, alt));
+                }
+            },
+            ast::Stmt::Seq(stmts) => {
+                for s in stmts {
+                    self.stmt(block, s);
+                }
+            }
+            ast::Stmt::Let(x, rhs) => {
+                let rhs = self.exp(*rhs);
+                match constant(&rhs) {
+                    Some(v) => self.env.insert(x.clone(), v.clone()),
+                    None => self.env.remove(&x),
+                };
+                block.push(ast::Stmt::Let(x, Box::new(rhs)));
+            }
+            ast::Stmt::SetGlobal(x, e) => block.push(ast::Stmt::SetGlobal(x, self.bexp(*e))),
+            ast::Stmt::SetIndex(a, i, e) => {
+                let i = self.bexp(*i);
+                block.push(ast::Stmt::SetIndex(a, i, self.bexp(*e)));
+            }
+            ast::Stmt::Asm(asm) => {
+                for x in &asm.outputs {
+                    self.env.remove(x);
+                }
+                block.push(ast::Stmt::Asm(asm));
+            }
+            ast::Stmt::Exp(e) => block.push(ast::Stmt::Exp(self.bexp(*e))),
+        }
+    }
+
+    // Runs `f` on one arm of a conditional; whatever the arm learns is forgotten afterwards.
+    fn branch<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let saved = self.env.clone();
+        let result = f(self);
+        self.env = saved;
+        result
+    }
+
+    // Lets inside a block may either shadow or overwrite an outer binding depending on where
+    // the block ends up after flattening, so anything they bind is no longer known on exit.
+    fn leave_scope(&mut self, saved: BTreeMap<Var, Value>, stmts: &[ast::Stmt]) {
+        self.env = saved;
+        for x in bound_vars(stmts) {
+            self.env.remove(&x);
+        }
+    }
+}
+
+// The value `e` always produces, looking past any lets in front of it (such as an
+// inlined body's) since those cannot change it.
+fn constant(e: &ast::Exp) -> Option<&Value> {
+    match e {
+        ast::Exp::Value(v) => Some(v),
+        ast::Exp::Seq(_, body) => constant(body),
+        _ => None,
+    }
+}
+
+fn bound_vars(stmts: &[ast::Stmt]) -> BTreeSet<Var> {
+    let mut vars = BTreeSet::new();
+    for s in stmts {
+        stmt_bound_vars(&mut vars, s);
+    }
+    vars
+}
+
+fn stmt_bound_vars(vars: &mut BTreeSet<Var>, s: &ast::Stmt) {
+    match s {
+        ast::Stmt::If(test, conseq, alt) => {
+            pred_bound_vars(vars, test);
+            vars.extend(bound_vars(conseq));
+            vars.extend(bound_vars(alt));
+        }
+        ast::Stmt::Seq(stmts) => vars.extend(bound_vars(stmts)),
+        ast::Stmt::Let(x, rhs) => {
+            vars.insert(x.clone());
+            exp_bound_vars(vars, rhs);
+        }
+        ast::Stmt::SetGlobal(_, e) | ast::Stmt::Exp(e) => exp_bound_vars(vars, e),
+        ast::Stmt::SetIndex(_, i, e) => {
+            exp_bound_vars(vars, i);
+            exp_bound_vars(vars, e);
+        }
+        ast::Stmt::Asm(asm) => vars.extend(asm.outputs.iter().cloned()),
+    }
+}
+
+fn exp_bound_vars(vars: &mut BTreeSet<Var>, e: &ast::Exp) {
+    match e {
+        ast::Exp::Call(subject, args) => {
+            exp_bound_vars(vars, subject);
+            for arg in args {
+                exp_bound_vars(vars, arg);
+            }
+        }
+        ast::Exp::Seq(stmts, body) => {
+            vars.extend(bound_vars(stmts));
+            exp_bound_vars(vars, body);
+        }
+        ast::Exp::If(test, conseq, alt) => {
+            pred_bound_vars(vars, test);
+            exp_bound_vars(vars, conseq);
+            exp_bound_vars(vars, alt);
+        }
+        ast::Exp::Binop(lhs, _, rhs) => {
+            exp_bound_vars(vars, lhs);
+            exp_bound_vars(vars, rhs);
+        }
+        ast::Exp::Index(_, i) => exp_bound_vars(vars, i),
+        ast::Exp::Value(_) | ast::Exp::Var(_) | ast::Exp::Global(_) => (),
+    }
+}
+
+fn pred_bound_vars(vars: &mut BTreeSet<Var>, p: &ast::Pred) {
+    match p {
+        ast::Pred::Call(subject, args) => {
+            exp_bound_vars(vars, subject);
+            for arg in args {
+                exp_bound_vars(vars, arg);
+            }
+        }
+        ast::Pred::Relop(lhs, _, rhs) => {
+            exp_bound_vars(vars, lhs);
+            exp_bound_vars(vars, rhs);
+        }
+        ast::Pred::Seq(stmts, body) => {
+            vars.extend(bound_vars(stmts));
+            pred_bound_vars(vars, body);
+        }
+        ast::Pred::If(test, conseq, alt) => {
+            pred_bound_vars(vars, test);
+            pred_bound_vars(vars, conseq);
+            pred_bound_vars(vars, alt);
+        }
+        ast::Pred::True | ast::Pred::False => (),
+    }
+}
+
+fn make_block(block: Vec<ast::Stmt>, exp: ast::Exp) -> ast::Exp {
+    match exp {
+        exp if block.is_empty() => exp,
+        ast::Exp::Seq(stmts, base) => {
+            let stmts = block.into_iter().chain(stmts).collect();
+            ast::Exp::Seq(stmts, base)
+        }
+        exp @ (ast::Exp::Call(_, _)
+        | ast::Exp::If(_, _, _)
+        | ast::Exp::Var(_)
+        | ast::Exp::Global(_)
+        | ast::Exp::Index(_, _)
+        | ast::Exp::Value(_)
+        | ast::Exp::Binop(_, _, _)) => ast::Exp::Seq(block, Box::new(exp)),
+    }
+}
+
+fn make_pred_block(block: Vec<ast::Stmt>, pred: ast::Pred) -> ast::Pred {
+    match pred {
+        pred if block.is_empty() => pred,
+        ast::Pred::Seq(stmts, base) => {
+            let stmts = block.into_iter().chain(stmts).collect();
+            ast::Pred::Seq(stmts, base)
+        }
+        pred @ (ast::Pred::Call(_, _)
+        | ast::Pred::Relop(_, _, _)
+        | ast::Pred::If(_, _, _)
+        | ast::Pred::True
+        | ast::Pred::False) => ast::Pred::Seq(block, Box::new(pred)),
+    }
+}
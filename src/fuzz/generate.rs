@@ -0,0 +1,425 @@
+use std::collections::BTreeMap;
+
+use crate::fuzz::Rng;
+use crate::input::{Exp, Stmt};
+use crate::shared::ast::{Func, Global, Op, Program, Value, Var};
+
+// Generates programs the interpreter can run to completion: every variable is bound
+// before use, bytes, words and booleans are not mixed in arithmetic, and a function
+// only calls the ones after it, directly or through their addresses, or itself in
+// tail position with a counter that stops it within a few rounds, so calls always
+// bottom out. Names are reused, both in blocks side by side and to shadow a
+// variable or a function; a function a variable shadows is called by address.
+// Lambdas capture freely, since they are only ever called and so never need a
+// record. The entry point is `main`, which nothing calls. Globals hold bytes or words and array elements bytes, most indices
+// are constants in bounds, and tables are never stored to.
+#[derive(Clone, Copy, PartialEq)]
+enum Ty {
+    Int,
+    Word,
+    Bool,
+    // Only in scope, for a local array of this length.
+    Array(usize),
+}
+
+pub struct Generator<'a> {
+    rng: &'a mut Rng,
+    counter: u32,
+    funcs: Vec<Var>,
+    // Names and arities of the functions the current one may call.
+    callees: Vec<(Var, usize)>,
+    globals: Vec<Var>,
+    words: Vec<Var>,
+    arrays: Vec<(Var, usize)>,
+    tables: Vec<(Var, usize)>,
+}
+
+const DEPTH: u32 = 4;
+
+pub fn program(rng: &mut Rng) -> Program<Exp> {
+    let count = 1 + rng.below(4);
+    let names = (0..count)
+        .map(|i| match i {
+            0 => "main".to_string(),
+            i => format!("f{}", i),
+        })
+        .collect::<Vec<_>>();
+    let arities = (0..count).map(|_| rng.below(3)).collect::<Vec<_>>();
+    let mut globals = (1..=rng.below(3))
+        .map(|i| {
+            (
+                format!("g{}", i),
+                Global::Value(Value::Int(rng.below(256) as u8)),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    let bytes = globals.keys().cloned().collect();
+    let words = (1..=rng.below(2))
+        .map(|i| format!("w{}", i))
+        .collect::<Vec<_>>();
+    for w in &words {
+        globals.insert(
+            w.clone(),
+            Global::Value(Value::Word(rng.below(65536) as u16)),
+        );
+    }
+    let arrays = (1..=rng.below(2))
+        .map(|i| (format!("a{}", i), 1 + rng.below(8)))
+        .collect::<Vec<_>>();
+    for (a, len) in &arrays {
+        globals.insert(a.clone(), Global::Array(*len));
+    }
+    let mut tables = vec![];
+    for i in 1..=rng.below(2) {
+        let bytes = (0..1 + rng.below(8))
+            .map(|_| rng.below(256) as u8)
+            .collect::<Vec<_>>();
+        tables.push((format!("t{}", i), bytes.len()));
+        globals.insert(format!("t{}", i), Global::Table(bytes));
+    }
+    let mut generator = Generator {
+        rng,
+        counter: 0,
+        funcs: names.clone(),
+        callees: vec![],
+        globals: bytes,
+        words,
+        arrays,
+        tables,
+    };
+    let mut funcs = BTreeMap::new();
+    for i in (0..count).rev() {
+        generator.callees = names[i + 1..]
+            .iter()
+            .cloned()
+            .zip(arities[i + 1..].iter().copied())
+            .collect();
+        let params = (0..arities[i])
+            .map(|j| format!("p{}", j))
+            .collect::<Vec<_>>();
+        let mut scope = params.iter().map(|x| (x.clone(), Ty::Int)).collect();
+        let body = if i > 0 && !params.is_empty() && generator.rng.below(3) == 0 {
+            generator.tail_loop(&names[i], &params, &mut scope)
+        } else {
+            generator.exp(Ty::Int, DEPTH, &mut scope)
+        };
+        funcs.insert(names[i].clone(), Func { params, body });
+    }
+    Program { globals, funcs }
+}
+
+impl Generator<'_> {
+    fn fresh(&mut self) -> Var {
+        self.counter += 1;
+        format!("v{}", self.counter)
+    }
+
+    // A name for a new variable: usually a fresh one, but sometimes one used before,
+    // which may be in scope, or a function's.
+    fn name(&mut self) -> Var {
+        match self.rng.below(8) {
+            0 | 1 if self.counter > 0 => format!("v{}", 1 + self.rng.below(self.counter as usize)),
+            2 => self.funcs[self.rng.below(self.funcs.len())].clone(),
+            _ => self.fresh(),
+        }
+    }
+
+    fn exp(&mut self, ty: Ty, depth: u32, scope: &mut Vec<(Var, Ty)>) -> Exp {
+        if depth == 0 || self.rng.below(4) == 0 {
+            return self.leaf(ty, scope);
+        }
+        let depth = depth - 1;
+        match (ty, self.rng.below(5)) {
+            (Ty::Int, 0) if self.callees.is_empty() || self.rng.below(4) == 0 => {
+                self.lambda_call(depth, scope)
+            }
+            (Ty::Int, 0) => {
+                let (f, arity) = self.callees[self.rng.below(self.callees.len())].clone();
+                let args = (0..arity)
+                    .map(|_| self.exp(Ty::Int, depth, scope))
+                    .collect();
+                let subject = self.subject(f, arity, depth, scope);
+                Exp::Call(Box::new(subject), args)
+            }
+            (Ty::Int, 1) => {
+                let op = match self.rng.below(9) {
+                    0 => Op::BitSet,
+                    1 => Op::BitClear,
+                    2 => Op::Carry,
+                    3 => Op::Borrow,
+                    4 => Op::Byte,
+                    n if n % 2 == 0 => Op::Add,
+                    _ => Op::Sub,
+                };
+                let lhs = match op {
+                    Op::Byte => self.exp(Ty::Word, depth, scope),
+                    _ => self.exp(Ty::Int, depth, scope),
+                };
+                let rhs = match op {
+                    Op::BitSet | Op::BitClear => self.bit(),
+                    Op::Byte => Exp::Value(Value::Int(self.rng.below(2) as u8)),
+                    _ => self.exp(Ty::Int, depth, scope),
+                };
+                Exp::Binop(Box::new(lhs), op, Box::new(rhs))
+            }
+            (Ty::Word, 0 | 1) => {
+                let (op, operands) = match self.rng.below(3) {
+                    0 => (Op::Word, Ty::Int),
+                    1 => (Op::Add, Ty::Word),
+                    _ => (Op::Sub, Ty::Word),
+                };
+                let lhs = self.exp(operands, depth, scope);
+                let rhs = self.exp(operands, depth, scope);
+                Exp::Binop(Box::new(lhs), op, Box::new(rhs))
+            }
+            // Questions only bytes can answer.
+            (Ty::Bool, 0 | 1) if self.rng.below(3) == 0 => {
+                let lhs = self.exp(Ty::Int, depth, scope);
+                if self.rng.below(2) == 0 {
+                    Exp::Binop(Box::new(lhs), Op::BitTest, Box::new(self.bit()))
+                } else {
+                    let rhs = self.exp(Ty::Int, depth, scope);
+                    Exp::Binop(Box::new(lhs), Op::Overflow, Box::new(rhs))
+                }
+            }
+            (Ty::Bool, 0 | 1) => {
+                let op = if self.rng.below(2) == 0 {
+                    Op::Eq
+                } else {
+                    Op::Neq
+                };
+                let operands = match self.rng.below(6) {
+                    0 => Ty::Bool,
+                    1 => Ty::Word,
+                    _ => Ty::Int,
+                };
+                let lhs = self.exp(operands, depth, scope);
+                let rhs = self.exp(operands, depth, scope);
+                Exp::Binop(Box::new(lhs), op, Box::new(rhs))
+            }
+            (_, 2) => {
+                // Any value can be tested, though only FALSE is false.
+                let test_ty = if self.rng.below(4) == 0 {
+                    Ty::Int
+                } else {
+                    Ty::Bool
+                };
+                let test = self.exp(test_ty, depth, scope);
+                let conseq = self.exp(ty, depth, scope);
+                let alt = self.exp(ty, depth, scope);
+                Exp::If(Box::new(test), Box::new(conseq), Box::new(alt))
+            }
+            (_, 3) => {
+                let outer = scope.len();
+                let stmts = (0..1 + self.rng.below(3))
+                    .map(|_| self.stmt(depth, scope))
+                    .collect();
+                let body = self.exp(ty, depth, scope);
+                scope.truncate(outer);
+                Exp::Seq(stmts, Box::new(body))
+            }
+            (Ty::Int, 4) => match self.array(scope, false) {
+                Some((a, len)) => {
+                    let i = self.index(len, depth, scope);
+                    Exp::Index(a, Box::new(i))
+                }
+                None => self.leaf(ty, scope),
+            },
+            _ => self.leaf(ty, scope),
+        }
+    }
+
+    // A global array or a local one in scope, or a table if it is only to be read.
+    fn array(&mut self, scope: &[(Var, Ty)], store: bool) -> Option<(Var, usize)> {
+        let tables = if store { &[][..] } else { &self.tables[..] };
+        let arrays = visible(scope)
+            .filter_map(|(a, ty)| match ty {
+                Ty::Array(len) => Some((a.clone(), *len)),
+                _ => None,
+            })
+            .chain(self.arrays.iter().cloned())
+            .chain(tables.iter().cloned())
+            .collect::<Vec<_>>();
+        match arrays.len() {
+            0 => None,
+            n => Some(arrays[self.rng.below(n)].clone()),
+        }
+    }
+
+    // Anything but a constant will usually be out of bounds, and the program is then
+    // thrown away, so those are kept rare.
+    fn index(&mut self, len: usize, depth: u32, scope: &mut Vec<(Var, Ty)>) -> Exp {
+        if self.rng.below(4) == 0 {
+            self.exp(Ty::Int, depth, scope)
+        } else {
+            Exp::Value(Value::Int(self.rng.below(len) as u8))
+        }
+    }
+
+    // Mostly the callee's name, but sometimes its address, or a choice between its
+    // address and another function's that takes as many arguments.
+    fn subject(&mut self, f: Var, arity: usize, depth: u32, scope: &mut Vec<(Var, Ty)>) -> Exp {
+        match self.rng.below(4) {
+            0 => Exp::Value(Value::Func(f)),
+            1 => {
+                let others = self
+                    .callees
+                    .iter()
+                    .filter(|(_, n)| *n == arity)
+                    .map(|(g, _)| g.clone())
+                    .collect::<Vec<_>>();
+                let g = others[self.rng.below(others.len())].clone();
+                Exp::If(
+                    Box::new(self.exp(Ty::Bool, depth, scope)),
+                    Box::new(Exp::Value(Value::Func(f))),
+                    Box::new(Exp::Value(Value::Func(g))),
+                )
+            }
+            _ if visible(scope).any(|(x, _)| *x == f) => Exp::Value(Value::Func(f)),
+            _ => Exp::Var(f),
+        }
+    }
+
+    // A lambda over what is in scope, called on the spot or through a variable.
+    fn lambda_call(&mut self, depth: u32, scope: &mut Vec<(Var, Ty)>) -> Exp {
+        let mut params = Vec::<Var>::new();
+        for _ in 0..self.rng.below(3) {
+            let x = self.name();
+            params.push(if params.contains(&x) { self.fresh() } else { x });
+        }
+        let on_spot = self.rng.below(2) == 0;
+        let mut inner = scope.clone();
+        inner.extend(params.iter().map(|p| (p.clone(), Ty::Int)));
+        let body = self.exp(Ty::Int, depth, &mut inner);
+        let args = params
+            .iter()
+            .map(|_| self.exp(Ty::Int, depth, scope))
+            .collect();
+        let lambda = Exp::Lambda(params, Box::new(body));
+        if on_spot {
+            return Exp::Call(Box::new(lambda), args);
+        }
+        // The arguments are evaluated where the variable is bound, so it must not
+        // hide anything they read.
+        let f = match self.name() {
+            f if visible(scope).any(|(x, _)| *x == f) => self.fresh(),
+            f => f,
+        };
+        Exp::Seq(
+            vec![Stmt::Let(f.clone(), Box::new(lambda))],
+            Box::new(Exp::Call(Box::new(Exp::Var(f)), args)),
+        )
+    }
+
+    // A loop written as a call `f` makes to itself in tail position. The first
+    // parameter goes up by 32 each time round until its top bit is set, so it stops
+    // within four rounds; the others are often passed on in another order, as a swap
+    // would, which makes the copies back to the loop's start cross.
+    fn tail_loop(&mut self, f: &Var, params: &[Var], scope: &mut Vec<(Var, Ty)>) -> Exp {
+        let counter = || Box::new(Exp::Var(params[0].clone()));
+        let done = Exp::Binop(counter(), Op::BitTest, Box::new(Exp::Value(Value::Int(7))));
+        let base = self.exp(Ty::Int, DEPTH - 1, scope);
+        let mut args = vec![Exp::Binop(
+            counter(),
+            Op::Add,
+            Box::new(Exp::Value(Value::Int(32))),
+        )];
+        for _ in 1..params.len() {
+            args.push(if self.rng.below(2) == 0 {
+                Exp::Var(params[self.rng.below(params.len())].clone())
+            } else {
+                self.exp(Ty::Int, 1, scope)
+            });
+        }
+        Exp::If(
+            Box::new(done),
+            Box::new(base),
+            Box::new(Exp::Call(Box::new(Exp::Var(f.clone())), args)),
+        )
+    }
+
+    fn bit(&mut self) -> Exp {
+        Exp::Value(Value::Int(self.rng.below(8) as u8))
+    }
+
+    fn stmt(&mut self, depth: u32, scope: &mut Vec<(Var, Ty)>) -> Stmt {
+        let ty = match self.rng.below(6) {
+            0 | 1 => Ty::Bool,
+            2 => Ty::Word,
+            _ => Ty::Int,
+        };
+        if !self.globals.is_empty() && self.rng.below(4) == 0 {
+            let g = self.globals[self.rng.below(self.globals.len())].clone();
+            return Stmt::SetGlobal(g, Box::new(self.exp(Ty::Int, depth, scope)));
+        }
+        if !self.words.is_empty() && self.rng.below(6) == 0 {
+            let w = self.words[self.rng.below(self.words.len())].clone();
+            return Stmt::SetGlobal(w, Box::new(self.exp(Ty::Word, depth, scope)));
+        }
+        if self.rng.below(4) == 0 {
+            if let Some((a, len)) = self.array(scope, true) {
+                let i = self.index(len, depth, scope);
+                let e = self.exp(Ty::Int, depth, scope);
+                return Stmt::SetIndex(a, Box::new(i), Box::new(e));
+            }
+        }
+        if self.rng.below(8) == 0 {
+            let a = self.fresh();
+            let len = 1 + self.rng.below(8);
+            scope.push((a.clone(), Ty::Array(len)));
+            return Stmt::Array(a, len);
+        }
+        let e = self.exp(ty, depth, scope);
+        if self.rng.below(4) == 0 {
+            return Stmt::Exp(Box::new(e));
+        }
+        let x = self.name();
+        scope.push((x.clone(), ty));
+        Stmt::Let(x, Box::new(e))
+    }
+
+    fn leaf(&mut self, ty: Ty, scope: &[(Var, Ty)]) -> Exp {
+        if ty == Ty::Int && !self.globals.is_empty() && self.rng.below(4) == 0 {
+            return Exp::Global(self.globals[self.rng.below(self.globals.len())].clone());
+        }
+        if ty == Ty::Word && !self.words.is_empty() && self.rng.below(4) == 0 {
+            return Exp::Global(self.words[self.rng.below(self.words.len())].clone());
+        }
+        let vars = visible(scope).filter(|(_, t)| *t == ty).collect::<Vec<_>>();
+        if !vars.is_empty() && self.rng.below(3) != 0 {
+            return Exp::Var(vars[self.rng.below(vars.len())].0.clone());
+        }
+        match ty {
+            // Bias towards the bytes where wrapping and comparisons get interesting.
+            Ty::Int => Exp::Value(Value::Int(match self.rng.below(4) {
+                0 => 0,
+                1 => 1,
+                2 => 255,
+                _ => self.rng.below(256) as u8,
+            })),
+            // And the words where a carry crosses into the high byte.
+            Ty::Word => Exp::Value(Value::Word(match self.rng.below(5) {
+                0 => 0,
+                1 => 255,
+                2 => 256,
+                3 => 65535,
+                _ => self.rng.below(65536) as u16,
+            })),
+            Ty::Bool if self.rng.below(2) == 0 => Exp::Value(Value::True),
+            Ty::Bool => Exp::Value(Value::False),
+            Ty::Array(_) => unreachable!("arrays are not values"),
+        }
+    }
+}
+
+// The bindings in scope that a later one of the same name does not hide. Arrays and
+// variables are named apart, and arrays always get fresh names.
+fn visible(scope: &[(Var, Ty)]) -> impl Iterator<Item = &(Var, Ty)> {
+    let array = |ty: &Ty| matches!(ty, Ty::Array(_));
+    scope.iter().enumerate().filter_map(move |(i, (x, ty))| {
+        let hidden = scope[i + 1..]
+            .iter()
+            .any(|(y, t)| y == x && array(t) == array(ty));
+        (!hidden).then_some(&scope[i])
+    })
+}
@@ -0,0 +1,121 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::convert_closures;
+use crate::input::Exp;
+use crate::interpret::{self, Error};
+use crate::pipeline::{compile, Options};
+use crate::shared::ast::{Program, Value};
+use crate::shared::ToDoc;
+use crate::types;
+
+pub mod generate;
+pub mod shrink;
+
+// xorshift64*: enough randomness for generating programs, and reproducible from
+// the seed alone.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15 | 1)
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) % n as u64) as usize
+    }
+}
+
+// Generated programs are small and loop-free, so this only runs out on a bug.
+const STEPS: usize = 100_000;
+
+enum Failure {
+    Panic(String),
+    Mismatch {
+        expected: Value,
+        actual: Result<Value, Error>,
+    },
+}
+
+// Compiles random programs and checks each computes what the reference interpreter
+// says it does. The first failure is shrunk and reported; returns whether there
+// were none.
+pub fn run(iterations: usize, seed: u64) -> bool {
+    let mut rng = Rng::new(seed);
+    // The pipeline reports broken invariants by panicking; those are caught and
+    // reported here instead.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let mut passed = true;
+    for i in 0..iterations {
+        let program = generate::program(&mut rng);
+        let args = (0..program.funcs["main"].params.len())
+            .map(|_| Value::Int(rng.below(256) as u8))
+            .collect::<Vec<_>>();
+        if check(&program, &args).is_none() {
+            continue;
+        }
+        let program = shrink(program, &args);
+        println!("program {} from seed {} fails, shrunk to:", i, seed);
+        let _ = program.to_doc().render(80, &mut std::io::stdout());
+        println!("\nrun as (main {:?})", args);
+        match check(&program, &args) {
+            Some(Failure::Panic(message)) => println!("compiling it panics: {}", message),
+            Some(Failure::Mismatch { expected, actual }) => {
+                println!("expected {:?}, compiled code gives {:?}", expected, actual)
+            }
+            None => unreachable!("shrinking keeps the failure"),
+        }
+        passed = false;
+        break;
+    }
+    panic::set_hook(hook);
+    passed
+}
+
+fn check(program: &Program<Exp>, args: &[Value]) -> Option<Failure> {
+    // Shrinking makes plenty of programs that no longer run; they prove nothing. Nor
+    // do ones the compiler rejects, for types that do not fit or closures that would
+    // share a record.
+    types::check(program).ok()?;
+    convert_closures::pass::check(program).ok()?;
+    let expected = interpret::input::run(program, "main", args, STEPS).ok()?;
+    // A pass that leaves something malformed is a failure even if the result runs.
+    let options = Options {
+        verify: true,
+        ..Options::default()
+    };
+    let compiled = panic::catch_unwind(AssertUnwindSafe(|| compile(program.clone(), &options)));
+    let compiled = match compiled {
+        Ok(compiled) => compiled,
+        Err(payload) => {
+            let message = match payload.downcast::<String>() {
+                Ok(message) => *message,
+                Err(payload) => match payload.downcast::<&str>() {
+                    Ok(message) => message.to_string(),
+                    Err(_) => "(no message)".to_string(),
+                },
+            };
+            return Some(Failure::Panic(message));
+        }
+    };
+    let actual = interpret::cfg::run(&compiled, "main", args, STEPS);
+    if actual == Ok(expected.clone()) {
+        None
+    } else {
+        Some(Failure::Mismatch { expected, actual })
+    }
+}
+
+// Greedily takes the first smaller program that still fails until none does.
+fn shrink(mut program: Program<Exp>, args: &[Value]) -> Program<Exp> {
+    while let Some(smaller) = shrink::candidates(&program)
+        .into_iter()
+        .find(|candidate| check(candidate, args).is_some())
+    {
+        program = smaller;
+    }
+    program
+}
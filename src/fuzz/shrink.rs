@@ -0,0 +1,130 @@
+use crate::input::{Exp, Stmt};
+use crate::shared::ast::{Func, Program, Value};
+
+// Every program one step smaller than `program`: a function dropped, a subexpression
+// replaced by 0 or by one of its own subexpressions, or a statement removed. Many of
+// these no longer run, which the caller finds out by running them.
+pub fn candidates(program: &Program<Exp>) -> Vec<Program<Exp>> {
+    let mut out = vec![];
+    for name in program.funcs.keys().filter(|name| *name != "main") {
+        let mut smaller = program.clone();
+        smaller.funcs.remove(name);
+        out.push(smaller);
+    }
+    for (name, func) in &program.funcs {
+        for body in exp(&func.body) {
+            let mut smaller = program.clone();
+            smaller.funcs.insert(
+                name.clone(),
+                Func {
+                    params: func.params.clone(),
+                    body,
+                },
+            );
+            out.push(smaller);
+        }
+    }
+    out
+}
+
+fn exp(e: &Exp) -> Vec<Exp> {
+    let mut out = vec![];
+    if *e != Exp::Value(Value::Int(0)) {
+        out.push(Exp::Value(Value::Int(0)));
+    }
+    match e {
+        Exp::Call(subject, args) => {
+            out.extend(args.iter().cloned());
+            for (i, arg) in args.iter().enumerate() {
+                for arg in exp(arg) {
+                    let mut args = args.clone();
+                    args[i] = arg;
+                    out.push(Exp::Call(subject.clone(), args));
+                }
+            }
+            for subject in exp(subject) {
+                out.push(Exp::Call(Box::new(subject), args.clone()));
+            }
+        }
+        Exp::Seq(stmts, body) => {
+            out.push((**body).clone());
+            for i in 0..stmts.len() {
+                let mut stmts = stmts.clone();
+                stmts.remove(i);
+                out.push(Exp::Seq(stmts, body.clone()));
+            }
+            for (i, s) in stmts.iter().enumerate() {
+                for s in stmt(s) {
+                    let mut stmts = stmts.clone();
+                    stmts[i] = s;
+                    out.push(Exp::Seq(stmts, body.clone()));
+                }
+            }
+            for body in exp(body) {
+                out.push(Exp::Seq(stmts.clone(), Box::new(body)));
+            }
+        }
+        Exp::Binop(lhs, op, rhs) => {
+            out.push((**lhs).clone());
+            out.push((**rhs).clone());
+            for lhs in exp(lhs) {
+                out.push(Exp::Binop(Box::new(lhs), op.clone(), rhs.clone()));
+            }
+            for rhs in exp(rhs) {
+                out.push(Exp::Binop(lhs.clone(), op.clone(), Box::new(rhs)));
+            }
+        }
+        Exp::If(test, conseq, alt) => {
+            out.push((**conseq).clone());
+            out.push((**alt).clone());
+            for test in exp(test) {
+                out.push(Exp::If(Box::new(test), conseq.clone(), alt.clone()));
+            }
+            for conseq in exp(conseq) {
+                out.push(Exp::If(test.clone(), Box::new(conseq), alt.clone()));
+            }
+            for alt in exp(alt) {
+                out.push(Exp::If(test.clone(), conseq.clone(), Box::new(alt)));
+            }
+        }
+        Exp::Index(a, i) => {
+            for i in exp(i) {
+                out.push(Exp::Index(a.clone(), Box::new(i)));
+            }
+        }
+        Exp::Lambda(params, body) => {
+            for body in exp(body) {
+                out.push(Exp::Lambda(params.clone(), Box::new(body)));
+            }
+        }
+        Exp::Value(_) | Exp::Var(_) | Exp::Global(_) => (),
+    }
+    out
+}
+
+fn stmt(s: &Stmt) -> Vec<Stmt> {
+    match s {
+        Stmt::Let(x, e) => exp(e)
+            .into_iter()
+            .map(|e| Stmt::Let(x.clone(), Box::new(e)))
+            .collect(),
+        Stmt::SetGlobal(x, e) => exp(e)
+            .into_iter()
+            .map(|e| Stmt::SetGlobal(x.clone(), Box::new(e)))
+            .collect(),
+        Stmt::SetIndex(a, i, e) => {
+            let mut out = exp(i)
+                .into_iter()
+                .map(|i| Stmt::SetIndex(a.clone(), Box::new(i), e.clone()))
+                .collect::<Vec<_>>();
+            out.extend(
+                exp(e)
+                    .into_iter()
+                    .map(|e| Stmt::SetIndex(a.clone(), i.clone(), Box::new(e))),
+            );
+            out
+        }
+        Stmt::Array(_, _) | Stmt::Asm(_) => vec![],
+        Stmt::Exp(e) => exp(e).into_iter().map(|e| Stmt::Exp(Box::new(e))).collect(),
+    }
+}
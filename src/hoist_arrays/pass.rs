@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::input::{self, Exp, Stmt};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Func, Global, Program, Value, Var};
+use crate::shared::names::Names;
+
+// The KENBAK has no stack to put an array on, so a local array is a global that only
+// its function can name: `(array buf 8)` in f becomes the global `f.buf`, or `f.buf.1`
+// and so on if something has that name already, and every `buf[i]` it scopes over is
+// renamed to match. Like any global it is zeroed when the program loads and keeps its
+// contents between calls.
+pub struct Pass<'a> {
+    func: Var,
+    hoisted: BTreeMap<Var, Global>,
+    names: &'a mut Names,
+}
+
+impl Stage for Pass<'_> {
+    type Input = Exp;
+    type Output = Exp;
+    const NAME: &'static str = "hoist_arrays";
+
+    fn run(program: Program<Exp>, names: &mut Names) -> Program<Exp> {
+        Pass::run(program, names)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<Exp>, names: &mut Names) -> Program<Exp> {
+        let Program { mut globals, funcs } = program;
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let mut pass = Pass {
+                func: name.clone(),
+                hoisted: BTreeMap::new(),
+                names,
+            };
+            let body = pass.exp(body, &BTreeMap::new());
+            globals.append(&mut pass.hoisted);
+            output_funcs.insert(name, Func { params, body });
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    // `scope` maps each local array in scope to the global it became.
+    fn exp(&mut self, e: Exp, scope: &BTreeMap<Var, Var>) -> Exp {
+        match e {
+            Exp::Call(subject, args) => Exp::Call(
+                self.bexp(*subject, scope),
+                args.into_iter().map(|arg| self.exp(arg, scope)).collect(),
+            ),
+            Exp::Seq(stmts, body) => {
+                let mut scope = scope.clone();
+                let mut block = vec![];
+                for stmt in stmts {
+                    match stmt {
+                        Stmt::Exp(e) => block.push(Stmt::Exp(self.bexp(*e, &scope))),
+                        Stmt::Let(x, e) => block.push(Stmt::Let(x, self.bexp(*e, &scope))),
+                        Stmt::SetGlobal(x, e) => {
+                            block.push(Stmt::SetGlobal(x, self.bexp(*e, &scope)))
+                        }
+                        Stmt::SetIndex(a, i, e) => block.push(Stmt::SetIndex(
+                            resolve(&scope, a),
+                            self.bexp(*i, &scope),
+                            self.bexp(*e, &scope),
+                        )),
+                        s @ Stmt::Asm(_) => block.push(s),
+                        Stmt::Array(a, len) => {
+                            let global = self.hoisted_name(&a);
+                            self.hoisted.insert(global.clone(), Global::Array(len));
+                            scope.insert(a, global);
+                        }
+                    }
+                }
+                let body = self.bexp(*body, &scope);
+                if block.is_empty() {
+                    *body
+                } else {
+                    Exp::Seq(block, body)
+                }
+            }
+            Exp::Binop(lhs, op, rhs) => {
+                Exp::Binop(self.bexp(*lhs, scope), op, self.bexp(*rhs, scope))
+            }
+            Exp::If(test, conseq, alt) => Exp::If(
+                self.bexp(*test, scope),
+                self.bexp(*conseq, scope),
+                self.bexp(*alt, scope),
+            ),
+            Exp::Index(a, i) => Exp::Index(resolve(scope, a), self.bexp(*i, scope)),
+            // A lambda's arrays, and the ones it can see, belong to the function it is in.
+            Exp::Lambda(params, body) => Exp::Lambda(params, self.bexp(*body, scope)),
+            e @ (Exp::Value(_) | Exp::Var(_) | Exp::Global(_)) => e,
+        }
+    }
+
+    fn bexp(&mut self, e: Exp, scope: &BTreeMap<Var, Var>) -> Box<Exp> {
+        Box::new(self.exp(e, scope))
+    }
+
+    fn hoisted_name(&mut self, array: &Var) -> Var {
+        let name = format!("{}.{}", self.func, array);
+        if self.names.claim(&name) {
+            name
+        } else {
+            self.names.fresh(&format!("{}.", name))
+        }
+    }
+}
+
+// Anything not declared locally is a global array.
+fn resolve(scope: &BTreeMap<Var, Var>, a: Var) -> Var {
+    scope.get(&a).cloned().unwrap_or(a)
+}
+
+// What is wrong with a source program's arrays that can be seen without running it:
+// indexing something that is not an array, a constant index past the end, a store
+// into a table, reading or storing a plain global that is not one, a local array
+// declared twice, and more arrays than fit in memory once the local ones are hoisted.
+pub fn check(program: &Program<Exp>) -> Result<(), String> {
+    for (name, func) in &program.funcs {
+        let mut checker = Checker {
+            program,
+            func: name,
+            declared: BTreeSet::new(),
+        };
+        checker.exp(&func.body, &BTreeMap::new())?;
+    }
+    let mut names = Names::new(input::names(program));
+    Pass::run(program.clone(), &mut names).global_addresses()?;
+    Ok(())
+}
+
+struct Checker<'a> {
+    program: &'a Program<Exp>,
+    func: &'a Var,
+    // Every local array declared in the function so far, scoped or not.
+    declared: BTreeSet<&'a Var>,
+}
+
+impl<'a> Checker<'a> {
+    fn exp(&mut self, e: &'a Exp, scope: &BTreeMap<&'a Var, usize>) -> Result<(), String> {
+        match e {
+            Exp::Call(subject, args) => {
+                self.exp(subject, scope)?;
+                args.iter().try_for_each(|arg| self.exp(arg, scope))
+            }
+            Exp::Seq(stmts, body) => {
+                let mut scope = scope.clone();
+                for stmt in stmts {
+                    match stmt {
+                        Stmt::Exp(e) | Stmt::Let(_, e) => self.exp(e, &scope)?,
+                        Stmt::SetGlobal(x, e) => {
+                            self.global(x)?;
+                            self.exp(e, &scope)?;
+                        }
+                        Stmt::SetIndex(a, i, e) => {
+                            self.index(a, i, &scope, true)?;
+                            self.exp(e, &scope)?;
+                        }
+                        Stmt::Asm(_) => (),
+                        Stmt::Array(a, len) => {
+                            if !self.declared.insert(a) {
+                                return Err(format!(
+                                    "{}: array {} is declared twice",
+                                    self.func, a
+                                ));
+                            }
+                            scope.insert(a, *len);
+                        }
+                    }
+                }
+                self.exp(body, &scope)
+            }
+            Exp::Binop(lhs, _, rhs) => {
+                self.exp(lhs, scope)?;
+                self.exp(rhs, scope)
+            }
+            Exp::If(test, conseq, alt) => {
+                self.exp(test, scope)?;
+                self.exp(conseq, scope)?;
+                self.exp(alt, scope)
+            }
+            Exp::Index(a, i) => self.index(a, i, scope, false),
+            Exp::Lambda(_, body) => self.exp(body, scope),
+            Exp::Global(x) => self.global(x),
+            Exp::Value(_) | Exp::Var(_) => Ok(()),
+        }
+    }
+
+    fn global(&self, x: &Var) -> Result<(), String> {
+        match self.program.globals.get(x) {
+            Some(Global::Value(_)) => Ok(()),
+            Some(Global::Array(_)) => Err(format!(
+                "{}: {} is an array, not a plain global",
+                self.func, x
+            )),
+            Some(Global::Table(_)) => Err(format!(
+                "{}: {} is a table, not a plain global",
+                self.func, x
+            )),
+            None => Err(format!("{}: no global {}", self.func, x)),
+        }
+    }
+
+    fn index(
+        &mut self,
+        a: &Var,
+        i: &'a Exp,
+        scope: &BTreeMap<&'a Var, usize>,
+        store: bool,
+    ) -> Result<(), String> {
+        let len = match (scope.get(a), self.program.globals.get(a)) {
+            (Some(len), _) | (None, Some(Global::Array(len))) => *len,
+            (None, Some(Global::Table(_))) if store => {
+                return Err(format!(
+                    "{}: {} is a table, which is read-only",
+                    self.func, a
+                ))
+            }
+            (None, Some(Global::Table(bytes))) => bytes.len(),
+            (None, Some(Global::Value(_))) => {
+                return Err(format!("{}: {} is not an array", self.func, a))
+            }
+            (None, None) => return Err(format!("{}: no array {}", self.func, a)),
+        };
+        if let Exp::Value(Value::Int(n)) = i {
+            if usize::from(*n) >= len {
+                return Err(format!(
+                    "{}: {}[{}] is past the end of its {} bytes",
+                    self.func, a, n, len
+                ));
+            }
+        }
+        self.exp(i, scope)
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::input::{Exp, Stmt};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Asm, Func, Global, Program, Value, Var};
+use crate::shared::names::Names;
+
+// Every call costs pushes, a jump-and-mark and a return on the KENBAK, so anything
+// about the size of a few arithmetic steps is cheaper to copy than to call.
+pub const DEFAULT_THRESHOLD: usize = 16;
+
+pub struct Pass<'a> {
+    inlinable: BTreeMap<Var, Func<Exp>>,
+    names: &'a mut Names,
+}
+
+// The pipeline inlines at the default threshold.
+impl Stage for Pass<'_> {
+    type Input = Exp;
+    type Output = Exp;
+    const NAME: &'static str = "inline_functions";
+
+    fn run(program: Program<Exp>, names: &mut Names) -> Program<Exp> {
+        Pass::run(program, DEFAULT_THRESHOLD, names)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<Exp>, threshold: usize, names: &mut Names) -> Program<Exp> {
+        let Program { globals, funcs } = program;
+        let recursive = recursive_funcs(&funcs);
+        let inlinable = funcs
+            .iter()
+            .filter(|(name, func)| !recursive.contains(*name) && size(&func.body) <= threshold)
+            .map(|(name, func)| (name.clone(), func.clone()))
+            .collect();
+        let called_before = called_funcs(&globals, &funcs);
+        let mut pass = Pass { inlinable, names };
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let body = pass.exp(body, &params.iter().cloned().collect());
+            output_funcs.insert(name, Func { params, body });
+        }
+        // Functions nobody called to begin with are entry points, so only drop the ones
+        // whose last caller was inlined away.
+        let called_after = called_funcs(&globals, &output_funcs);
+        output_funcs.retain(|name, _| !called_before.contains(name) || called_after.contains(name));
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    fn fresh(&mut self, x: &Var) -> Var {
+        self.names.fresh(&format!("{}.inline.", x))
+    }
+
+    // `bound` are the variables in scope. A call through one of them is indirect even
+    // if a function has the same name.
+    fn exp(&mut self, e: Exp, bound: &BTreeSet<Var>) -> Exp {
+        match e {
+            Exp::Call(subject, args) => {
+                let args = args
+                    .into_iter()
+                    .map(|arg| self.exp(arg, bound))
+                    .collect::<Vec<_>>();
+                let callee = match &*subject {
+                    Exp::Var(f) if !bound.contains(f) => Some(f),
+                    Exp::Value(Value::Func(f)) => Some(f),
+                    _ => None,
+                };
+                match callee {
+                    Some(f)
+                        if self
+                            .inlinable
+                            .get(f)
+                            .is_some_and(|func| func.params.len() == args.len()) =>
+                    {
+                        let f = f.clone();
+                        self.inline(&f, args)
+                    }
+                    _ => Exp::Call(self.bexp(*subject, bound), args),
+                }
+            }
+            Exp::Seq(stmts, body) => {
+                let mut bound = bound.clone();
+                let stmts = stmts
+                    .into_iter()
+                    .map(|stmt| match stmt {
+                        Stmt::Exp(e) => Stmt::Exp(self.bexp(*e, &bound)),
+                        Stmt::Let(x, e) => {
+                            let e = self.bexp(*e, &bound);
+                            bound.insert(x.clone());
+                            Stmt::Let(x, e)
+                        }
+                        Stmt::SetGlobal(x, e) => Stmt::SetGlobal(x, self.bexp(*e, &bound)),
+                        Stmt::SetIndex(a, i, e) => {
+                            Stmt::SetIndex(a, self.bexp(*i, &bound), self.bexp(*e, &bound))
+                        }
+                        s @ Stmt::Array(_, _) => s,
+                        Stmt::Asm(asm) => {
+                            bound.extend(asm.outputs.iter().cloned());
+                            Stmt::Asm(asm)
+                        }
+                    })
+                    .collect();
+                Exp::Seq(stmts, self.bexp(*body, &bound))
+            }
+            Exp::Binop(lhs, op, rhs) => {
+                Exp::Binop(self.bexp(*lhs, bound), op, self.bexp(*rhs, bound))
+            }
+            Exp::If(test, conseq, alt) => Exp::If(
+                self.bexp(*test, bound),
+                self.bexp(*conseq, bound),
+                self.bexp(*alt, bound),
+            ),
+            Exp::Index(a, i) => Exp::Index(a, self.bexp(*i, bound)),
+            e @ (Exp::Value(_) | Exp::Var(_) | Exp::Global(_) | Exp::Lambda(_, _)) => e,
+        }
+    }
+
+    fn bexp(&mut self, e: Exp, bound: &BTreeSet<Var>) -> Box<Exp> {
+        Box::new(self.exp(e, bound))
+    }
+
+    // Binds each argument to a fresh copy of its parameter, then splices in the callee's
+    // body with every parameter and let renamed so nothing at the call site is captured.
+    // The functions it calls by name are called by address instead, so a variable at
+    // the call site with the same name cannot capture those either.
+    fn inline(&mut self, f: &Var, args: Vec<Exp>) -> Exp {
+        let Func { params, body } = self.inlinable[f].clone();
+        let mut renaming = BTreeMap::new();
+        let mut block = vec![];
+        for (param, arg) in params.into_iter().zip(args) {
+            let fresh = self.fresh(&param);
+            block.push(Stmt::Let(fresh.clone(), Box::new(arg)));
+            renaming.insert(param, fresh);
+        }
+        let body = self.rename(&renaming, body);
+        // The callee is not recursive, so inlining into its copy terminates.
+        let body = self.exp(body, &renaming.into_values().collect());
+        if block.is_empty() {
+            body
+        } else {
+            Exp::Seq(block, Box::new(body))
+        }
+    }
+
+    fn rename(&mut self, renaming: &BTreeMap<Var, Var>, e: Exp) -> Exp {
+        match e {
+            Exp::Call(subject, args) => {
+                let subject = match *subject {
+                    Exp::Var(f) if !renaming.contains_key(&f) => Exp::Value(Value::Func(f)),
+                    subject => self.rename(renaming, subject),
+                };
+                Exp::Call(
+                    Box::new(subject),
+                    args.into_iter()
+                        .map(|arg| self.rename(renaming, arg))
+                        .collect(),
+                )
+            }
+            Exp::Seq(stmts, body) => {
+                let mut renaming = renaming.clone();
+                let mut block = vec![];
+                for stmt in stmts {
+                    block.push(match stmt {
+                        Stmt::Exp(e) => Stmt::Exp(Box::new(self.rename(&renaming, *e))),
+                        Stmt::Let(x, e) => {
+                            let e = self.rename(&renaming, *e);
+                            let fresh = self.fresh(&x);
+                            renaming.insert(x, fresh.clone());
+                            Stmt::Let(fresh, Box::new(e))
+                        }
+                        // Globals are shared with the caller, so they keep their names.
+                        Stmt::SetGlobal(x, e) => {
+                            Stmt::SetGlobal(x, Box::new(self.rename(&renaming, *e)))
+                        }
+                        Stmt::SetIndex(a, i, e) => Stmt::SetIndex(
+                            a,
+                            Box::new(self.rename(&renaming, *i)),
+                            Box::new(self.rename(&renaming, *e)),
+                        ),
+                        // By now every local array has been hoisted to a global.
+                        s @ Stmt::Array(_, _) => s,
+                        // Outputs are assigned like lets.
+                        Stmt::Asm(Asm {
+                            outputs,
+                            inputs,
+                            body,
+                        }) => {
+                            let inputs = inputs
+                                .into_iter()
+                                .map(|x| renaming.get(&x).cloned().unwrap_or(x))
+                                .collect();
+                            let outputs = outputs
+                                .into_iter()
+                                .map(|x| {
+                                    let fresh = self.fresh(&x);
+                                    renaming.insert(x, fresh.clone());
+                                    fresh
+                                })
+                                .collect();
+                            Stmt::Asm(Asm {
+                                outputs,
+                                inputs,
+                                body,
+                            })
+                        }
+                    });
+                }
+                Exp::Seq(block, Box::new(self.rename(&renaming, *body)))
+            }
+            Exp::Binop(lhs, op, rhs) => Exp::Binop(
+                Box::new(self.rename(renaming, *lhs)),
+                op,
+                Box::new(self.rename(renaming, *rhs)),
+            ),
+            Exp::If(test, conseq, alt) => Exp::If(
+                Box::new(self.rename(renaming, *test)),
+                Box::new(self.rename(renaming, *conseq)),
+                Box::new(self.rename(renaming, *alt)),
+            ),
+            Exp::Index(a, i) => Exp::Index(a, Box::new(self.rename(renaming, *i))),
+            Exp::Lambda(_, _) => unreachable!("lambda was not converted"),
+            e @ (Exp::Value(_) | Exp::Global(_)) => e,
+            // Anything not renamed is free in the callee, i.e. another function's name.
+            Exp::Var(x) => Exp::Var(renaming.get(&x).cloned().unwrap_or(x)),
+        }
+    }
+}
+
+fn size(e: &Exp) -> usize {
+    match e {
+        Exp::Call(subject, args) => 1 + size(subject) + args.iter().map(size).sum::<usize>(),
+        Exp::Seq(stmts, body) => {
+            let stmts = stmts
+                .iter()
+                .map(|stmt| match stmt {
+                    Stmt::Exp(e) => size(e),
+                    Stmt::Let(_, e) | Stmt::SetGlobal(_, e) => 1 + size(e),
+                    Stmt::SetIndex(_, i, e) => 1 + size(i) + size(e),
+                    Stmt::Array(_, _) => 0,
+                    Stmt::Asm(asm) => asm.body.len(),
+                })
+                .sum::<usize>();
+            1 + stmts + size(body)
+        }
+        Exp::Binop(lhs, _, rhs) => 1 + size(lhs) + size(rhs),
+        Exp::If(test, conseq, alt) => 1 + size(test) + size(conseq) + size(alt),
+        Exp::Index(_, i) => 1 + size(i),
+        Exp::Lambda(_, body) => 1 + size(body),
+        Exp::Value(_) | Exp::Var(_) | Exp::Global(_) => 1,
+    }
+}
+
+// Every function mentioned in some function body, whether called or passed around,
+// or stored in a global when the program loads.
+pub fn called_funcs(
+    globals: &BTreeMap<Var, Global>,
+    funcs: &BTreeMap<Var, Func<Exp>>,
+) -> BTreeSet<Var> {
+    let mut called = globals
+        .values()
+        .filter_map(|global| match global {
+            Global::Value(Value::Func(f)) => Some(f.clone()),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+    for func in funcs.values() {
+        mentions(&mut called, funcs, &func.body);
+    }
+    called
+}
+
+fn recursive_funcs(funcs: &BTreeMap<Var, Func<Exp>>) -> BTreeSet<Var> {
+    let edges = funcs
+        .iter()
+        .map(|(name, func)| {
+            let mut callees = BTreeSet::new();
+            mentions(&mut callees, funcs, &func.body);
+            (name, callees)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let mut recursive = BTreeSet::new();
+    for name in funcs.keys() {
+        let mut seen = BTreeSet::new();
+        let mut stack = edges[name].iter().collect::<Vec<_>>();
+        while let Some(callee) = stack.pop() {
+            if callee == name {
+                recursive.insert(name.clone());
+                break;
+            }
+            if seen.insert(callee) {
+                stack.extend(edges[callee].iter());
+            }
+        }
+    }
+    recursive
+}
+
+fn mentions(out: &mut BTreeSet<Var>, funcs: &BTreeMap<Var, Func<Exp>>, e: &Exp) {
+    match e {
+        Exp::Call(subject, args) => {
+            mentions(out, funcs, subject);
+            for arg in args {
+                mentions(out, funcs, arg);
+            }
+        }
+        Exp::Seq(stmts, body) => {
+            for stmt in stmts {
+                match stmt {
+                    Stmt::Exp(e) | Stmt::Let(_, e) | Stmt::SetGlobal(_, e) => {
+                        mentions(out, funcs, e)
+                    }
+                    Stmt::SetIndex(_, i, e) => {
+                        mentions(out, funcs, i);
+                        mentions(out, funcs, e);
+                    }
+                    Stmt::Array(_, _) => (),
+                    Stmt::Asm(asm) => {
+                        for x in &asm.inputs {
+                            if funcs.contains_key(x) {
+                                out.insert(x.clone());
+                            }
+                        }
+                    }
+                }
+            }
+            mentions(out, funcs, body);
+        }
+        Exp::Binop(lhs, _, rhs) => {
+            mentions(out, funcs, lhs);
+            mentions(out, funcs, rhs);
+        }
+        Exp::If(test, conseq, alt) => {
+            mentions(out, funcs, test);
+            mentions(out, funcs, conseq);
+            mentions(out, funcs, alt);
+        }
+        Exp::Index(_, i) => mentions(out, funcs, i),
+        Exp::Lambda(_, body) => mentions(out, funcs, body),
+        Exp::Value(Value::Func(f)) => {
+            out.insert(f.clone());
+        }
+        Exp::Value(_) | Exp::Global(_) => (),
+        Exp::Var(x) => {
+            if funcs.contains_key(x) {
+                out.insert(x.clone());
+            }
+        }
+    }
+}
@@ -1,9 +1,15 @@
-use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::fmt;
 
-use crate::shared::ast::{Op, Value, Var};
+use pretty::RcDoc;
 
-#[derive(Clone)]
+use crate::shared::ast::{
+    parse_len, parse_position, parse_var, Asm, Global, Op, Program, Triv, Value, Var,
+};
+use crate::shared::read::{expected, malformed, parse_all, Sexp};
+use crate::shared::{index, sexp, Parse, ToDoc, Verify};
+
+#[derive(Clone, PartialEq)]
 pub enum Exp {
     Call(Box<Exp>, Vec<Exp>),
     Seq(Vec<Stmt>, Box<Exp>),
@@ -11,12 +17,31 @@ pub enum Exp {
     If(Box<Exp>, Box<Exp>, Box<Exp>),
     Value(Value),
     Var(Var),
+    // Reads a global; unlike a variable it can change under a call.
+    Global(Var),
+    Index(Var, Box<Exp>),
+    // An anonymous function that can use the variables in scope where it appears.
+    // convert_closures lifts each one into a function of its own, so nothing past it
+    // sees one.
+    Lambda(Vec<Var>, Box<Exp>),
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum Stmt {
     Exp(Box<Exp>),
+    // `(set! x e)` binds a new x for the rest of its block, like a `let` whose body is
+    // the rest of the block. It never changes an x bound outside the block, so one in
+    // an arm of an `if` is gone after it. The form is Scheme's but in a source program
+    // it does not assign; from rename_variables on every binding has a name of its
+    // own, and reading it as an assignment comes to the same thing.
     Let(Var, Box<Exp>),
+    SetGlobal(Var, Box<Exp>),
+    SetIndex(Var, Box<Exp>, Box<Exp>),
+    // An array only this function can see, from here to the end of the block. Like
+    // everything else on the KENBAK it is not on a stack: hoist_arrays makes it a
+    // global, so it starts out zero and keeps its contents from one call to the next.
+    Array(Var, usize),
+    Asm(Asm),
 }
 
 impl fmt::Debug for Exp {
@@ -54,6 +79,9 @@ impl fmt::Debug for Exp {
             }
             Exp::Value(v) => v.fmt(f),
             Exp::Var(x) => write!(f, "{}", x),
+            Exp::Global(x) => write!(f, "(global {})", x),
+            Exp::Index(a, i) => write!(f, "{}[{:?}]", a, i),
+            Exp::Lambda(params, body) => write!(f, "(lambda ({}) {:?})", params.join(" "), body),
         }
     }
 }
@@ -63,6 +91,378 @@ impl fmt::Debug for Stmt {
         match self {
             Stmt::Exp(e) => e.fmt(f),
             Stmt::Let(x, e) => write!(f, "(let {} = {:?})", x, e),
+            Stmt::SetGlobal(x, e) => write!(f, "(set-global! {} {:?})", x, e),
+            Stmt::SetIndex(a, i, e) => write!(f, "(set! {}[{:?}] {:?})", a, i, e),
+            Stmt::Array(a, len) => write!(f, "(array {} {})", a, len),
+            Stmt::Asm(asm) => asm.fmt(f),
+        }
+    }
+}
+
+impl ToDoc for Exp {
+    fn to_doc(&self) -> RcDoc<'_> {
+        match self {
+            Exp::Call(subject, args) => sexp(subject.to_doc(), args.iter().map(Exp::to_doc)),
+            Exp::Seq(stmts, body) => sexp(
+                RcDoc::text("begin"),
+                stmts.iter().map(Stmt::to_doc).chain([body.to_doc()]),
+            ),
+            Exp::Binop(lhs, op, rhs) => sexp(op.to_doc(), [lhs.to_doc(), rhs.to_doc()]),
+            Exp::If(test, conseq, alt) => sexp(
+                RcDoc::text("if"),
+                [test.to_doc(), conseq.to_doc(), alt.to_doc()],
+            ),
+            Exp::Value(v) => v.to_doc(),
+            Exp::Var(x) => RcDoc::text(x),
+            Exp::Global(x) => sexp(RcDoc::text("global"), [RcDoc::text(x)]),
+            Exp::Index(a, i) => index(a, i.to_doc()),
+            Exp::Lambda(params, body) => sexp(
+                RcDoc::text("lambda"),
+                [
+                    RcDoc::text(format!("({})", params.join(" "))),
+                    body.to_doc(),
+                ],
+            ),
+        }
+    }
+}
+
+impl ToDoc for Stmt {
+    fn to_doc(&self) -> RcDoc<'_> {
+        match self {
+            Stmt::Exp(e) => e.to_doc(),
+            Stmt::Let(x, e) => sexp(RcDoc::text("set!"), [RcDoc::text(x), e.to_doc()]),
+            Stmt::SetGlobal(x, e) => sexp(RcDoc::text("set-global!"), [RcDoc::text(x), e.to_doc()]),
+            Stmt::SetIndex(a, i, e) => {
+                sexp(RcDoc::text("set!"), [index(a, i.to_doc()), e.to_doc()])
+            }
+            Stmt::Array(a, len) => sexp(
+                RcDoc::text("array"),
+                [RcDoc::text(a), RcDoc::text(len.to_string())],
+            ),
+            Stmt::Asm(asm) => asm.to_doc(),
+        }
+    }
+}
+
+impl Parse for Exp {
+    fn parse(sexp: &Sexp) -> Result<Exp, String> {
+        let items = match sexp {
+            Sexp::Atom(_) => {
+                return match Triv::parse(sexp)? {
+                    Triv::Value(v) => Ok(Exp::Value(v)),
+                    Triv::Var(x) => Ok(Exp::Var(x)),
+                    Triv::Return => expected("an expression", sexp),
+                }
+            }
+            Sexp::List(items) => items,
+        };
+        match items.as_slice() {
+            [] => expected("an expression", sexp),
+            [head, rest @ ..] => match (head.atom(), rest) {
+                (Some("begin"), [stmts @ .., body]) => {
+                    Ok(Exp::Seq(parse_all(stmts)?, Box::new(Exp::parse(body)?)))
+                }
+                (Some("if"), [test, conseq, alt]) => Ok(Exp::If(
+                    Box::new(Exp::parse(test)?),
+                    Box::new(Exp::parse(conseq)?),
+                    Box::new(Exp::parse(alt)?),
+                )),
+                (
+                    Some("+" | "-" | "==" | "!=" | "carry" | "borrow" | "overflow" | "word"),
+                    [lhs, rhs],
+                ) => Ok(Exp::Binop(
+                    Box::new(Exp::parse(lhs)?),
+                    Op::parse(head)?,
+                    Box::new(Exp::parse(rhs)?),
+                )),
+                (Some("bit_test" | "bit_set" | "bit_clear" | "byte"), [x, n]) => {
+                    let op = Op::parse(head)?;
+                    let n = parse_position(&op, n)?;
+                    Ok(Exp::Binop(
+                        Box::new(Exp::parse(x)?),
+                        op,
+                        Box::new(Exp::Value(n)),
+                    ))
+                }
+                (Some("global"), [x]) => Ok(Exp::Global(parse_var(x)?)),
+                (Some("index"), [a, i]) => Ok(Exp::Index(parse_var(a)?, Box::new(Exp::parse(i)?))),
+                (Some("lambda"), [params, body]) => match params.list() {
+                    Some(params) => Ok(Exp::Lambda(
+                        params.iter().map(parse_var).collect::<Result<_, _>>()?,
+                        Box::new(Exp::parse(body)?),
+                    )),
+                    None => expected("(param ...)", params),
+                },
+                (
+                    Some(
+                        "begin" | "if" | "+" | "-" | "==" | "!=" | "bit_test" | "bit_set"
+                        | "bit_clear" | "carry" | "borrow" | "overflow" | "word" | "byte" | "set!"
+                        | "global" | "set-global!" | "index" | "array" | "asm" | "lambda",
+                    ),
+                    _,
+                ) => malformed(sexp),
+                _ => Ok(Exp::Call(Box::new(Exp::parse(head)?), parse_all(rest)?)),
+            },
+        }
+    }
+}
+
+impl Parse for Stmt {
+    fn parse(sexp: &Sexp) -> Result<Stmt, String> {
+        match sexp.form() {
+            Some(("set!", [target, e])) => match target.form() {
+                Some(("index", [a, i])) => Ok(Stmt::SetIndex(
+                    parse_var(a)?,
+                    Box::new(Exp::parse(i)?),
+                    Box::new(Exp::parse(e)?),
+                )),
+                // A binding, not an assignment: see `Stmt::Let`.
+                _ => Ok(Stmt::Let(parse_var(target)?, Box::new(Exp::parse(e)?))),
+            },
+            Some(("set-global!", [x, e])) => {
+                Ok(Stmt::SetGlobal(parse_var(x)?, Box::new(Exp::parse(e)?)))
+            }
+            Some(("array", [a, len])) => Ok(Stmt::Array(parse_var(a)?, parse_len(len)?)),
+            Some(("asm", _)) => Asm::parse(sexp).map(Stmt::Asm),
+            Some(("set!" | "set-global!" | "array", _)) => malformed(sexp),
+            _ => Ok(Stmt::Exp(Box::new(Exp::parse(sexp)?))),
+        }
+    }
+}
+
+// Only source programs declare local arrays; hoist_arrays runs first and turns them
+// into globals, and nothing after it puts one back.
+impl Verify for Exp {
+    fn verify(&self) -> Result<(), String> {
+        match self {
+            Exp::Call(subject, args) => {
+                subject.verify()?;
+                args.iter().try_for_each(Exp::verify)
+            }
+            Exp::Seq(stmts, body) => {
+                stmts.iter().try_for_each(Stmt::verify)?;
+                body.verify()
+            }
+            Exp::Binop(lhs, _, rhs) => {
+                lhs.verify()?;
+                rhs.verify()
+            }
+            Exp::If(test, conseq, alt) => {
+                test.verify()?;
+                conseq.verify()?;
+                alt.verify()
+            }
+            Exp::Index(_, i) => i.verify(),
+            Exp::Lambda(_, body) => body.verify(),
+            Exp::Value(_) | Exp::Var(_) | Exp::Global(_) => Ok(()),
+        }
+    }
+}
+
+impl Verify for Stmt {
+    fn verify(&self) -> Result<(), String> {
+        match self {
+            Stmt::Exp(e) | Stmt::Let(_, e) | Stmt::SetGlobal(_, e) => e.verify(),
+            Stmt::SetIndex(_, i, e) => {
+                i.verify()?;
+                e.verify()
+            }
+            Stmt::Array(a, _) => Err(format!("local array {} was not hoisted", a)),
+            Stmt::Asm(_) => Ok(()),
+        }
+    }
+}
+
+// Every name a program gives a global, a function or a variable, none of which a
+// name a pass makes up may be.
+pub fn names(program: &Program<Exp>) -> BTreeSet<Var> {
+    let mut out = program
+        .globals
+        .keys()
+        .chain(program.funcs.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    for func in program.funcs.values() {
+        out.extend(func.params.iter().cloned());
+        vars(&func.body, &mut out);
+    }
+    out
+}
+
+// Adds every variable and local array name `e` binds or reads.
+fn vars(e: &Exp, out: &mut BTreeSet<Var>) {
+    match e {
+        Exp::Call(subject, args) => {
+            vars(subject, out);
+            for arg in args {
+                vars(arg, out);
+            }
+        }
+        Exp::Seq(stmts, body) => {
+            for stmt in stmts {
+                match stmt {
+                    Stmt::Exp(e) | Stmt::SetGlobal(_, e) => vars(e, out),
+                    Stmt::Let(x, e) => {
+                        out.insert(x.clone());
+                        vars(e, out);
+                    }
+                    Stmt::SetIndex(_, i, e) => {
+                        vars(i, out);
+                        vars(e, out);
+                    }
+                    Stmt::Array(a, _) => {
+                        out.insert(a.clone());
+                    }
+                    Stmt::Asm(asm) => {
+                        out.extend(asm.inputs.iter().cloned());
+                        out.extend(asm.outputs.iter().cloned());
+                    }
+                }
+            }
+            vars(body, out);
+        }
+        Exp::Binop(lhs, _, rhs) => {
+            vars(lhs, out);
+            vars(rhs, out);
+        }
+        Exp::If(test, conseq, alt) => {
+            vars(test, out);
+            vars(conseq, out);
+            vars(alt, out);
+        }
+        Exp::Index(_, i) => vars(i, out),
+        Exp::Lambda(params, body) => {
+            out.extend(params.iter().cloned());
+            vars(body, out);
+        }
+        Exp::Var(x) => {
+            out.insert(x.clone());
+        }
+        Exp::Value(_) | Exp::Global(_) => (),
+    }
+}
+
+// What is wrong with a source program's names and calls that can be seen without
+// running it: a name that is neither a variable in scope nor a function, taking the
+// address of something that is not a function, calling a constant, and calling a
+// known function with the wrong number of arguments. Whether a call through a
+// variable or global calls a function at all is for types::check; how many
+// arguments it takes is checked when it runs.
+pub fn check(program: &Program<Exp>) -> Result<(), String> {
+    for (x, global) in &program.globals {
+        if let Global::Value(Value::Func(f)) = global {
+            if !program.funcs.contains_key(f) {
+                return Err(format!("{}: &{} names no function", x, f));
+            }
+        }
+    }
+    for (name, func) in &program.funcs {
+        let checker = Checker {
+            program,
+            func: name,
+        };
+        checker.exp(&func.body, &func.params.iter().collect())?;
+    }
+    Ok(())
+}
+
+struct Checker<'a> {
+    program: &'a Program<Exp>,
+    func: &'a Var,
+}
+
+impl<'a> Checker<'a> {
+    // `locals` are the variables in scope, which shadow functions of the same name.
+    fn exp(&self, e: &'a Exp, locals: &BTreeSet<&'a Var>) -> Result<(), String> {
+        match e {
+            Exp::Call(subject, args) => {
+                let callee = match &**subject {
+                    Exp::Var(f) if !locals.contains(f) => Some(f),
+                    Exp::Value(Value::Func(f)) => Some(f),
+                    Exp::Lambda(params, _) if params.len() != args.len() => {
+                        return Err(format!(
+                            "{}: lambda takes {} arguments, called with {}",
+                            self.func,
+                            params.len(),
+                            args.len()
+                        ))
+                    }
+                    Exp::Value(v) => {
+                        return Err(format!("{}: call of non-function {:?}", self.func, v))
+                    }
+                    _ => None,
+                };
+                if let Some(f) = callee {
+                    let func = match self.program.funcs.get(f) {
+                        Some(func) => func,
+                        None => return Err(format!("{}: no function {}", self.func, f)),
+                    };
+                    if func.params.len() != args.len() {
+                        return Err(format!(
+                            "{}: {} takes {} arguments, called with {}",
+                            self.func,
+                            f,
+                            func.params.len(),
+                            args.len()
+                        ));
+                    }
+                }
+                self.exp(subject, locals)?;
+                args.iter().try_for_each(|arg| self.exp(arg, locals))
+            }
+            Exp::Seq(stmts, body) => {
+                let mut locals = locals.clone();
+                for stmt in stmts {
+                    match stmt {
+                        Stmt::Exp(e) | Stmt::SetGlobal(_, e) => self.exp(e, &locals)?,
+                        Stmt::Let(x, e) => {
+                            self.exp(e, &locals)?;
+                            locals.insert(x);
+                        }
+                        Stmt::SetIndex(_, i, e) => {
+                            self.exp(i, &locals)?;
+                            self.exp(e, &locals)?;
+                        }
+                        Stmt::Array(_, _) => (),
+                        Stmt::Asm(asm) => {
+                            for x in &asm.inputs {
+                                self.var(x, &locals)?;
+                            }
+                            locals.extend(&asm.outputs);
+                        }
+                    }
+                }
+                self.exp(body, &locals)
+            }
+            Exp::Binop(lhs, _, rhs) => {
+                self.exp(lhs, locals)?;
+                self.exp(rhs, locals)
+            }
+            Exp::If(test, conseq, alt) => {
+                self.exp(test, locals)?;
+                self.exp(conseq, locals)?;
+                self.exp(alt, locals)
+            }
+            Exp::Index(_, i) => self.exp(i, locals),
+            Exp::Lambda(params, body) => {
+                let mut locals = locals.clone();
+                locals.extend(params);
+                self.exp(body, &locals)
+            }
+            Exp::Value(Value::Func(f)) if !self.program.funcs.contains_key(f) => {
+                Err(format!("{}: &{} names no function", self.func, f))
+            }
+            Exp::Var(x) => self.var(x, locals),
+            Exp::Value(_) | Exp::Global(_) => Ok(()),
+        }
+    }
+
+    fn var(&self, x: &Var, locals: &BTreeSet<&'a Var>) -> Result<(), String> {
+        if locals.contains(x) || self.program.funcs.contains_key(x) {
+            Ok(())
+        } else {
+            Err(format!("{}: {} is not bound", self.func, x))
         }
     }
 }
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use crate::build_cfg::ast::{Cfg, Rhs, Stmt, Terminator};
+use crate::interpret::{binop, Budget, Error, Memory};
+use crate::shared::ast::{Func, Program, Triv, Value, Var};
+
+// Runs the compiled form: one environment per call, blocks executed until a return.
+pub struct Interpreter<'a> {
+    funcs: &'a BTreeMap<Var, Func<Cfg>>,
+    memory: Memory,
+    budget: Budget,
+}
+
+pub fn run(
+    program: &Program<Cfg>,
+    name: &str,
+    args: &[Value],
+    steps: usize,
+) -> Result<Value, Error> {
+    let mut interpreter = Interpreter {
+        funcs: &program.funcs,
+        memory: Memory::new(&program.globals),
+        budget: Budget::new(steps),
+    };
+    interpreter.call(name, args.to_vec())
+}
+
+impl Interpreter<'_> {
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        let func = match self.funcs.get(name) {
+            Some(func) if func.params.len() == args.len() => func,
+            Some(_) => {
+                return Err(Error::Stuck(format!(
+                    "{} called with {} arguments",
+                    name,
+                    args.len()
+                )))
+            }
+            None => return Err(Error::Stuck(format!("no function {}", name))),
+        };
+        let mut env = func.params.iter().cloned().zip(args).collect();
+        self.budget.enter()?;
+        let result = self.cfg(&func.body, &mut env);
+        self.budget.leave();
+        result
+    }
+
+    fn cfg(&mut self, cfg: &Cfg, env: &mut BTreeMap<Var, Value>) -> Result<Value, Error> {
+        let mut label = 0;
+        loop {
+            let block = &cfg.blocks[label];
+            for stmt in &block.stmts {
+                self.budget.step()?;
+                match stmt {
+                    Stmt::Let(x, rhs) => {
+                        let v = match rhs {
+                            Rhs::Triv(t) => triv(t, env)?,
+                            Rhs::Binop(y, op, t) => binop(&var(y, env)?, op, &triv(t, env)?)?,
+                            Rhs::Global(g) => self.memory.load(g, None)?,
+                            Rhs::Index(a, i) => self.memory.load(a, Some(&triv(i, env)?))?,
+                        };
+                        env.insert(x.clone(), v);
+                    }
+                    Stmt::SetGlobal(g, t) => {
+                        let v = triv(t, env)?;
+                        self.memory.store(g, None, v)?;
+                    }
+                    Stmt::SetIndex(a, i, t) => {
+                        let (i, v) = (triv(i, env)?, triv(t, env)?);
+                        self.memory.store(a, Some(&i), v)?;
+                    }
+                    Stmt::Asm(_) => return Err(Error::Stuck("inline assembly".to_string())),
+                }
+            }
+            self.budget.step()?;
+            label = match &block.term {
+                Terminator::Jump(l) => *l,
+                Terminator::Branch(x, op, t, conseq, alt) => {
+                    if binop(&var(x, env)?, op, &triv(t, env)?)? == Value::True {
+                        *conseq
+                    } else {
+                        *alt
+                    }
+                }
+                Terminator::Call {
+                    dest,
+                    subject,
+                    args,
+                    next,
+                } => {
+                    let name = match subject {
+                        Triv::Var(f) if !env.contains_key(f) => f.clone(),
+                        _ => match triv(subject, env)? {
+                            Value::Func(f) => f,
+                            v => return Err(Error::Stuck(format!("call of non-function {:?}", v))),
+                        },
+                    };
+                    let args = args
+                        .iter()
+                        .map(|arg| triv(arg, env))
+                        .collect::<Result<_, _>>()?;
+                    let v = self.call(&name, args)?;
+                    if let Some(x) = dest {
+                        env.insert(x.clone(), v);
+                    }
+                    *next
+                }
+                Terminator::Return(t) => return triv(t, env),
+            };
+        }
+    }
+}
+
+fn var(x: &Var, env: &BTreeMap<Var, Value>) -> Result<Value, Error> {
+    match env.get(x) {
+        Some(v) => Ok(v.clone()),
+        None => Err(Error::Stuck(format!("{} is unbound", x))),
+    }
+}
+
+fn triv(t: &Triv, env: &BTreeMap<Var, Value>) -> Result<Value, Error> {
+    match t {
+        Triv::Value(v) => Ok(v.clone()),
+        Triv::Var(x) => var(x, env),
+        Triv::Return => Err(Error::Stuck("read of the return register".to_string())),
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+
+use crate::hoist_arrays;
+use crate::input::{self, Exp, Stmt};
+use crate::interpret::{binop, truthy, Budget, Error, Memory};
+use crate::shared::ast::{Func, Program, Value, Var};
+use crate::shared::names::Names;
+
+// `let` is lexical: a binding made inside a block is gone once the block ends.
+// Globals are not: a store is seen by every later load, in any function.
+pub struct Interpreter<'a> {
+    funcs: &'a BTreeMap<Var, Func<Exp>>,
+    memory: Memory<Val<'a>>,
+    budget: Budget,
+}
+
+// What a variable or a global holds here: a value, or a closure, which only the
+// source language has. Each closure keeps the variables in scope where its lambda
+// was evaluated, so two made from one lambda do not share them.
+#[derive(Clone)]
+enum Val<'a> {
+    Value(Value),
+    Closure {
+        params: &'a [Var],
+        body: &'a Exp,
+        env: BTreeMap<Var, Val<'a>>,
+    },
+}
+
+impl From<Value> for Val<'_> {
+    fn from(v: Value) -> Self {
+        Val::Value(v)
+    }
+}
+
+impl Val<'_> {
+    // Anything but calling a closure needs a plain value.
+    fn value(self) -> Result<Value, Error> {
+        match self {
+            Val::Value(v) => Ok(v),
+            Val::Closure { .. } => Err(Error::Stuck("closure used as a value".to_string())),
+        }
+    }
+}
+
+pub fn run(
+    program: &Program<Exp>,
+    name: &str,
+    args: &[Value],
+    steps: usize,
+) -> Result<Value, Error> {
+    // A local array is a global that only one function can name, which is all that
+    // hoisting it spells out.
+    let mut names = Names::new(input::names(program));
+    let program = hoist_arrays::pass::Pass::run(program.clone(), &mut names);
+    let mut interpreter = Interpreter {
+        funcs: &program.funcs,
+        memory: Memory::new(&program.globals),
+        budget: Budget::new(steps),
+    };
+    let args = args.iter().cloned().map(Val::Value).collect();
+    interpreter.call(name, args)?.value()
+}
+
+// Evaluates an expression with no local variables, such as a call with constant
+// arguments. Globals are not available either, since what they hold depends on
+// what ran before, so touching one gets stuck.
+pub fn eval(program: &Program<Exp>, e: &Exp, steps: usize) -> Result<Value, Error> {
+    let mut interpreter = Interpreter {
+        funcs: &program.funcs,
+        memory: Memory::new(&BTreeMap::new()),
+        budget: Budget::new(steps),
+    };
+    interpreter.exp(e, &mut BTreeMap::new())?.value()
+}
+
+impl<'a> Interpreter<'a> {
+    fn call(&mut self, name: &str, args: Vec<Val<'a>>) -> Result<Val<'a>, Error> {
+        let func = match self.funcs.get(name) {
+            Some(func) if func.params.len() == args.len() => func,
+            Some(_) => {
+                return Err(Error::Stuck(format!(
+                    "{} called with {} arguments",
+                    name,
+                    args.len()
+                )))
+            }
+            None => return Err(Error::Stuck(format!("no function {}", name))),
+        };
+        self.enter(&func.params, &func.body, BTreeMap::new(), args)
+    }
+
+    fn enter(
+        &mut self,
+        params: &'a [Var],
+        body: &'a Exp,
+        mut env: BTreeMap<Var, Val<'a>>,
+        args: Vec<Val<'a>>,
+    ) -> Result<Val<'a>, Error> {
+        env.extend(params.iter().cloned().zip(args));
+        self.budget.enter()?;
+        let result = self.exp(body, &mut env);
+        self.budget.leave();
+        result
+    }
+
+    fn exp(&mut self, e: &'a Exp, env: &mut BTreeMap<Var, Val<'a>>) -> Result<Val<'a>, Error> {
+        self.budget.step()?;
+        match e {
+            Exp::Call(subject, args) => {
+                // A name no variable shadows calls that function directly; anything
+                // else has to evaluate to a function's address or a closure.
+                let subject = match &**subject {
+                    Exp::Var(f) if !env.contains_key(f) => Val::Value(Value::Func(f.clone())),
+                    _ => self.exp(subject, env)?,
+                };
+                let args = args
+                    .iter()
+                    .map(|arg| self.exp(arg, env))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match subject {
+                    Val::Value(Value::Func(f)) => self.call(&f, args),
+                    Val::Closure { params, body, env } if params.len() == args.len() => {
+                        self.enter(params, body, env, args)
+                    }
+                    Val::Closure { params, .. } => Err(Error::Stuck(format!(
+                        "lambda takes {} arguments, called with {}",
+                        params.len(),
+                        args.len()
+                    ))),
+                    Val::Value(v) => Err(Error::Stuck(format!("call of non-function {:?}", v))),
+                }
+            }
+            Exp::Seq(stmts, body) => {
+                let mut env = env.clone();
+                for stmt in stmts {
+                    match stmt {
+                        Stmt::Let(x, e) => {
+                            let v = self.exp(e, &mut env)?;
+                            env.insert(x.clone(), v);
+                        }
+                        Stmt::SetGlobal(x, e) => {
+                            let v = self.exp(e, &mut env)?;
+                            self.memory.store(x, None, v)?;
+                        }
+                        Stmt::SetIndex(a, i, e) => {
+                            let i = self.exp(i, &mut env)?.value()?;
+                            let v = self.exp(e, &mut env)?;
+                            self.memory.store(a, Some(&i), v)?;
+                        }
+                        Stmt::Asm(_) => return Err(Error::Stuck("inline assembly".to_string())),
+                        Stmt::Array(a, _) => {
+                            return Err(Error::Stuck(format!("local array {} not hoisted", a)))
+                        }
+                        Stmt::Exp(e) => {
+                            self.exp(e, &mut env)?;
+                        }
+                    }
+                }
+                self.exp(body, &mut env)
+            }
+            Exp::Binop(lhs, op, rhs) => {
+                let lhs = self.exp(lhs, env)?.value()?;
+                let rhs = self.exp(rhs, env)?.value()?;
+                binop(&lhs, op, &rhs).map(Val::Value)
+            }
+            Exp::If(test, conseq, alt) => {
+                let test = match self.exp(test, env)? {
+                    Val::Value(v) => truthy(&v),
+                    Val::Closure { .. } => true,
+                };
+                if test {
+                    self.exp(conseq, env)
+                } else {
+                    self.exp(alt, env)
+                }
+            }
+            Exp::Value(v) => Ok(Val::Value(v.clone())),
+            Exp::Var(x) => match env.get(x) {
+                Some(v) => Ok(v.clone()),
+                None => Err(Error::Stuck(format!("{} is unbound", x))),
+            },
+            Exp::Lambda(params, body) => Ok(Val::Closure {
+                params,
+                body,
+                env: env.clone(),
+            }),
+            Exp::Global(x) => self.memory.load(x, None),
+            Exp::Index(a, i) => {
+                let i = self.exp(i, env)?.value()?;
+                self.memory.load(a, Some(&i))
+            }
+        }
+    }
+}
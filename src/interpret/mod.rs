@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use crate::shared::ast::{Global, Op, Value, Var};
+
+pub mod cfg;
+pub mod input;
+
+// Reference semantics for the first and last IRs, so what a program computes can be
+// compared before and after compiling it. Both count steps against a budget, so a
+// program that does not terminate is reported instead of hanging the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    OutOfSteps,
+    // Too many nested calls to keep evaluating on the host stack.
+    TooDeep,
+    // Something the program does has no meaning, like adding TRUE.
+    Stuck(String),
+}
+
+pub struct Budget {
+    steps: usize,
+    depth: usize,
+}
+
+const MAX_DEPTH: usize = 256;
+
+impl Budget {
+    pub fn new(steps: usize) -> Budget {
+        Budget { steps, depth: 0 }
+    }
+
+    pub fn step(&mut self) -> Result<(), Error> {
+        match self.steps.checked_sub(1) {
+            Some(steps) => {
+                self.steps = steps;
+                Ok(())
+            }
+            None => Err(Error::OutOfSteps),
+        }
+    }
+
+    pub fn enter(&mut self) -> Result<(), Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn leave(&mut self) {
+        self.depth -= 1;
+    }
+}
+
+// The globals, each as the bytes it takes up. A plain global is an array of one
+// that is only reached by name, never by index. What a byte holds is `V`, so an
+// interpreter with values of its own can store them.
+pub struct Memory<V = Value> {
+    globals: BTreeMap<Var, Cells<V>>,
+}
+
+struct Cells<V> {
+    indexed: bool,
+    writable: bool,
+    values: Vec<V>,
+}
+
+impl<V: Clone + From<Value>> Memory<V> {
+    pub fn new(globals: &BTreeMap<Var, Global>) -> Memory<V> {
+        let globals = globals
+            .iter()
+            .map(|(name, global)| {
+                let (indexed, writable, values) = match global {
+                    Global::Value(init) => (false, true, vec![V::from(init.clone())]),
+                    Global::Array(len) => (true, true, vec![V::from(Value::Int(0)); *len]),
+                    Global::Table(bytes) => (
+                        true,
+                        false,
+                        bytes.iter().map(|b| V::from(Value::Int(*b))).collect(),
+                    ),
+                };
+                let cells = Cells {
+                    indexed,
+                    writable,
+                    values,
+                };
+                (name.clone(), cells)
+            })
+            .collect();
+        Memory { globals }
+    }
+
+    fn cell(&mut self, name: &Var, i: Option<&Value>) -> Result<&mut V, Error> {
+        let cells = match self.globals.get_mut(name) {
+            Some(cells) => cells,
+            None => return Err(Error::Stuck(format!("no global {}", name))),
+        };
+        let i = match (cells.indexed, i) {
+            (false, None) => 0,
+            (true, Some(Value::Int(i))) => usize::from(*i),
+            (true, None) => return Err(Error::Stuck(format!("{} is an array", name))),
+            (false, Some(_)) => return Err(Error::Stuck(format!("{} is not an array", name))),
+            (true, Some(i)) => return Err(Error::Stuck(format!("{}[{:?}]", name, i))),
+        };
+        let len = cells.values.len();
+        cells.values.get_mut(i).ok_or_else(|| {
+            Error::Stuck(format!("{}[{}] is past the end of {} bytes", name, i, len))
+        })
+    }
+
+    // `i` is `None` for a plain global and the index for an array.
+    pub fn load(&mut self, name: &Var, i: Option<&Value>) -> Result<V, Error> {
+        self.cell(name, i).cloned()
+    }
+
+    pub fn store(&mut self, name: &Var, i: Option<&Value>, v: V) -> Result<(), Error> {
+        if self.globals.get(name).is_some_and(|cells| !cells.writable) {
+            return Err(Error::Stuck(format!("{} is a table", name)));
+        }
+        *self.cell(name, i)? = v;
+        Ok(())
+    }
+}
+
+// Comparisons give TRUE or FALSE; anything `Op::apply` cannot do is stuck.
+pub fn binop(lhs: &Value, op: &Op, rhs: &Value) -> Result<Value, Error> {
+    op.apply(lhs, rhs)
+        .ok_or_else(|| Error::Stuck(format!("({:?} {:?} {:?})", op, lhs, rhs)))
+}
+
+// Anything but FALSE counts as true.
+pub fn truthy(v: &Value) -> bool {
+    *v != Value::False
+}
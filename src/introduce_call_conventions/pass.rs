@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
 
 use crate::introduce_call_conventions::ast;
-use crate::shared::ast::{Program, Triv};
+use crate::shared::ast::{Func, Program, Triv};
 use crate::normalize_context::ast as input;
 
 pub struct Pass {
@@ -13,8 +13,14 @@ impl Pass {
         let Program { funcs } = program;
         let mut pass = Pass { counter: 0 };
         let mut output_funcs = BTreeMap::new();
-        for (name, body) in funcs {
-            output_funcs.insert(name, pass.tail(body));
+        for (name, Func { params, body }) in funcs {
+            output_funcs.insert(
+                name,
+                Func {
+                    params,
+                    body: pass.tail(body),
+                },
+            );
         }
         Program {
             funcs: output_funcs,
@@ -1,141 +1,100 @@
-use std::collections::BTreeMap;
-
+mod build_cfg;
+mod convert_closures;
+mod convert_out_of_ssa;
+mod convert_to_ssa;
+mod evaluate_calls;
+mod fold_constants;
+mod fuzz;
+mod hoist_arrays;
+mod inline_functions;
 mod input;
+mod interpret;
 // mod introduce_call_conventions;
 mod normalize_context;
+mod number_values;
+mod pipeline;
+mod propagate_constants;
+mod propagate_copies;
+mod rename_variables;
 mod shared;
 mod simplify_values;
+mod types;
 
-use crate::input::{Exp, Stmt};
-// use crate::introduce_call_conventions::pass::Pass as icc;
-use crate::normalize_context::pass::Pass as nc;
-use crate::shared::ast::{Op, Program, Var};
-use crate::simplify_values::pass::Pass as sv;
-
-fn value(n: u8) -> Box<Exp> {
-    Box::new(Exp::Value(shared::ast::Value::Int(n)))
-}
-
-fn var(x: &str) -> Box<Exp> {
-    Box::new(Exp::Var(x.to_string()))
-}
-
-fn add(lhs: Box<Exp>, rhs: Box<Exp>) -> Box<Exp> {
-    Box::new(Exp::Binop(lhs, Op::Add, rhs))
-}
-
-fn sub(lhs: Box<Exp>, rhs: Box<Exp>) -> Box<Exp> {
-    Box::new(Exp::Binop(lhs, Op::Sub, rhs))
-}
-
-fn eq(lhs: Box<Exp>, rhs: Box<Exp>) -> Box<Exp> {
-    Box::new(Exp::Binop(lhs, Op::Eq, rhs))
-}
-
-fn call(subject: Box<Exp>, args: Vec<Exp>) -> Box<Exp> {
-    Box::new(Exp::Call(subject, args))
-}
-
-fn let_(name: &str, rhs: Box<Exp>, body: Box<Exp>) -> Box<Exp> {
-    Box::new(Exp::Seq(vec![Stmt::Let(name.to_string(), rhs)], body))
-}
+use crate::input::Exp;
+use crate::pipeline::{compile, Options};
+use crate::shared::ast::Program;
+use crate::shared::ToDoc;
 
-fn if_(test: Box<Exp>, conseq: Box<Exp>, alt: Box<Exp>) -> Box<Exp> {
-    Box::new(Exp::If(test, conseq, alt))
-}
+const USAGE: &str =
+    "usage: kenbak FILE [--emit=PASS] [--print-after=PASS|all] [--time-passes] [--dot]
+                   [--run=ARG,...] [--verify]
+       kenbak --fuzz=N [--seed=SEED]";
 
-/*
-fn test000() {
-    let e = call(
-        call(var("t"), vec![]),
-        vec![*add(add(value(10), value(20)), value(30))],
-    );
-    let funcs = BTreeMap::from([("fn".to_string(), *e)]);
-    let program = Program { funcs };
-    println!("\ninput program: {:#?}", program);
-    let Program { funcs } = icc::run(fs::run(sv::run(program)));
-    for (name, body) in funcs {
-        println!("name: {name}");
-        let _ = body.to_doc().render(100, &mut std::io::stdout());
-    }
-}
-*/
-fn test001() {
-    let e_inner = call(
-        call(var("t"), vec![]),
-        vec![*add(add(value(10), value(20)), value(30))],
-    );
-    let e = if_(e_inner.clone(), e_inner.clone(), e_inner);
+// How long `--run` lets a program go before deciding it does not terminate.
+const STEPS: usize = 10_000_000;
 
-    let funcs = BTreeMap::from([("fn".to_string(), *e)]);
-    let program = Program { funcs };
-    println!("\ninput program: {:?}", program);
-    let Program { funcs } = sv::run(nc::run(program));
-    for (name, body) in funcs {
-        println!("name: {name}");
-        println!("body: {:#?}", body);
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Some(iterations) = options.fuzz {
+        let seed = options.seed.unwrap_or_else(|| {
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+            now.map_or(0, |now| now.as_secs())
+        });
+        if !fuzz::run(iterations, seed) {
+            std::process::exit(1);
+        }
+        println!(
+            "{} programs from seed {} compiled correctly",
+            iterations, seed
+        );
+        return;
     }
-}
-
-fn test002() {
-    let n = var("n");
-    let e = if_(
-        eq(n.clone(), value(0)),
-        value(1),
-        if_(
-            eq(n.clone(), value(1)),
-            value(1),
-            add(
-                call(var("fib"), vec![*sub(n.clone(), value(1))]),
-                call(var("fib"), vec![*sub(n.clone(), value(2))]),
-            ),
-        ),
-    );
-    let funcs = BTreeMap::from([("fib".to_string(), *e)]);
-    let program = Program { funcs };
-    println!("\ninput program: {:?}\n", program);
-    // let Program { funcs } = icc::run(fs::run(sv::run(program)));
-    let Program { funcs } = sv::run(nc::run(program));
-    for (name, body) in funcs {
-        println!("name: {name}");
-        println!("body: {:#?}", body);
+    let Some(path) = &options.input else {
+        eprintln!("error: no input file\n{}", USAGE);
+        std::process::exit(2);
+    };
+    let program = match std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| Program::<Exp>::read(&text))
+    {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("error: {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = hoist_arrays::pass::check(&program)
+        .and_then(|()| input::check(&program))
+        .and_then(|()| types::check(&program))
+        .and_then(|()| convert_closures::pass::check(&program))
+    {
+        eprintln!("error: {}: {}", path, err);
+        std::process::exit(1);
     }
-}
-
-fn test003() {
-    let n = var("n");
-    let e = if_(
-        eq(n.clone(), value(0)),
-        value(1),
-        if_(
-            eq(n.clone(), value(1)),
-            value(1),
-            let_(
-                "rec",
-                add(
-                    call(var("fib"), vec![*sub(n.clone(), value(1))]),
-                    call(var("fib"), vec![*sub(n.clone(), value(2))]),
-                ),
-                add(
-                    var("rec"),
-                    call(var("fib"), vec![*sub(n.clone(), value(2))]),
-                ),
-            ),
-        ),
-    );
-    let funcs = BTreeMap::from([("fib".to_string(), *e)]);
-    let program = Program { funcs };
-    println!("\ninput program: {:?}\n", program);
-    // let Program { funcs } = icc::run(fs::run(sv::run(program)));
-    let Program { funcs } = sv::run(nc::run(program));
-    for (name, body) in funcs {
-        println!("name: {name}");
-        println!("body: {:#?}", body);
+    let program = compile(program, &options);
+    if let Some(args) = &options.run {
+        match interpret::cfg::run(&program, "main", args, STEPS) {
+            Ok(value) => {
+                let _ = value.to_doc().render(80, &mut std::io::stdout());
+                println!();
+            }
+            Err(err) => {
+                eprintln!("error: {}: main: {:?}", path, err);
+                std::process::exit(1);
+            }
+        }
+    } else if options.dot {
+        for (name, func) in &program.funcs {
+            print!("{}", func.body.to_dot(name));
+        }
+    } else if options.emit.is_none() {
+        let _ = program.to_doc().render(80, &mut std::io::stdout());
+        println!();
     }
 }
-
-fn main() {
-    // test000();
-    // test001();
-    test003();
-}
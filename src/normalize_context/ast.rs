@@ -1,6 +1,10 @@
-use crate::shared::ast::{Op, Value, Var};
+use pretty::RcDoc;
 
-#[derive(Debug, Clone)]
+use crate::shared::ast::{parse_position, parse_var, Asm, Op, Value, Var};
+use crate::shared::read::{expected, malformed, parse_all, Sexp};
+use crate::shared::{index, sexp, Parse, ToDoc, Verify};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Exp {
     Call(Box<Exp>, Vec<Exp>),
     Seq(Vec<Stmt>, Box<Exp>),
@@ -8,17 +12,22 @@ pub enum Exp {
     Binop(Box<Exp>, Op, Box<Exp>),
     Value(Value),
     Var(Var),
+    Global(Var),
+    Index(Var, Box<Exp>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Stmt {
     If(Box<Pred>, Vec<Stmt>, Vec<Stmt>),
     Seq(Vec<Stmt>),
     Let(Var, Box<Exp>),
+    SetGlobal(Var, Box<Exp>),
+    SetIndex(Var, Box<Exp>, Box<Exp>),
+    Asm(Asm),
     Exp(Box<Exp>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Pred {
     Call(Box<Exp>, Vec<Exp>),
     Relop(Box<Exp>, Op, Box<Exp>),
@@ -27,3 +36,295 @@ pub enum Pred {
     True,
     False,
 }
+
+impl ToDoc for Exp {
+    fn to_doc(&self) -> RcDoc<'_> {
+        match self {
+            Exp::Call(subject, args) => sexp(subject.to_doc(), args.iter().map(Exp::to_doc)),
+            Exp::Seq(stmts, body) => sexp(
+                RcDoc::text("begin"),
+                stmts.iter().map(Stmt::to_doc).chain([body.to_doc()]),
+            ),
+            Exp::If(test, conseq, alt) => sexp(
+                RcDoc::text("if"),
+                [test.to_doc(), conseq.to_doc(), alt.to_doc()],
+            ),
+            Exp::Binop(lhs, op, rhs) => sexp(op.to_doc(), [lhs.to_doc(), rhs.to_doc()]),
+            Exp::Value(v) => v.to_doc(),
+            Exp::Var(x) => RcDoc::text(x),
+            Exp::Global(x) => sexp(RcDoc::text("global"), [RcDoc::text(x)]),
+            Exp::Index(a, i) => index(a, i.to_doc()),
+        }
+    }
+}
+
+impl ToDoc for Stmt {
+    fn to_doc(&self) -> RcDoc<'_> {
+        match self {
+            Stmt::If(test, conseq, alt) => sexp(
+                RcDoc::text("if"),
+                [test.to_doc(), block_doc(conseq), block_doc(alt)],
+            ),
+            Stmt::Seq(stmts) => block_doc(stmts),
+            Stmt::Let(x, e) => sexp(RcDoc::text("set!"), [RcDoc::text(x), e.to_doc()]),
+            Stmt::SetGlobal(x, e) => sexp(RcDoc::text("set-global!"), [RcDoc::text(x), e.to_doc()]),
+            Stmt::SetIndex(a, i, e) => {
+                sexp(RcDoc::text("set!"), [index(a, i.to_doc()), e.to_doc()])
+            }
+            Stmt::Asm(asm) => asm.to_doc(),
+            Stmt::Exp(e) => match **e {
+                // Bare, these would read back as an `if` or `begin` statement.
+                Exp::Seq(_, _) | Exp::If(_, _, _) => sexp(RcDoc::text("exp"), [e.to_doc()]),
+                _ => e.to_doc(),
+            },
+        }
+    }
+}
+
+// The constant predicates are `#t` and `#f`, apart from the values TRUE and FALSE.
+impl ToDoc for Pred {
+    fn to_doc(&self) -> RcDoc<'_> {
+        match self {
+            Pred::Call(subject, args) => sexp(subject.to_doc(), args.iter().map(Exp::to_doc)),
+            Pred::Relop(lhs, op, rhs) => sexp(op.to_doc(), [lhs.to_doc(), rhs.to_doc()]),
+            Pred::Seq(stmts, body) => sexp(
+                RcDoc::text("begin"),
+                stmts.iter().map(Stmt::to_doc).chain([body.to_doc()]),
+            ),
+            Pred::If(test, conseq, alt) => sexp(
+                RcDoc::text("if"),
+                [test.to_doc(), conseq.to_doc(), alt.to_doc()],
+            ),
+            Pred::True => RcDoc::text("#t"),
+            Pred::False => RcDoc::text("#f"),
+        }
+    }
+}
+
+// Statement lists are always wrapped, even when empty, so an `if` statement's arms
+// stay apart.
+fn block_doc(stmts: &[Stmt]) -> RcDoc<'_> {
+    sexp(RcDoc::text("begin"), stmts.iter().map(Stmt::to_doc))
+}
+
+impl Parse for Exp {
+    fn parse(sexp: &Sexp) -> Result<Exp, String> {
+        let items = match sexp {
+            Sexp::Atom(a) if a.starts_with(|c: char| c.is_ascii_digit() || c == '&') => {
+                return Value::parse(sexp).map(Exp::Value)
+            }
+            Sexp::Atom(a) if a == "TRUE" || a == "FALSE" => {
+                return Value::parse(sexp).map(Exp::Value)
+            }
+            Sexp::Atom(_) => return parse_var(sexp).map(Exp::Var),
+            Sexp::List(items) => items,
+        };
+        match items.as_slice() {
+            [] => expected("an expression", sexp),
+            [head, rest @ ..] => match (head.atom(), rest) {
+                (Some("begin"), [stmts @ .., body]) => {
+                    Ok(Exp::Seq(parse_all(stmts)?, Box::new(Exp::parse(body)?)))
+                }
+                (Some("if"), [test, conseq, alt]) => Ok(Exp::If(
+                    Box::new(Pred::parse(test)?),
+                    Box::new(Exp::parse(conseq)?),
+                    Box::new(Exp::parse(alt)?),
+                )),
+                (
+                    Some("+" | "-" | "==" | "!=" | "carry" | "borrow" | "overflow" | "word"),
+                    [lhs, rhs],
+                ) => Ok(Exp::Binop(
+                    Box::new(Exp::parse(lhs)?),
+                    Op::parse(head)?,
+                    Box::new(Exp::parse(rhs)?),
+                )),
+                (Some("bit_test" | "bit_set" | "bit_clear" | "byte"), [x, n]) => {
+                    let op = Op::parse(head)?;
+                    let n = parse_position(&op, n)?;
+                    Ok(Exp::Binop(
+                        Box::new(Exp::parse(x)?),
+                        op,
+                        Box::new(Exp::Value(n)),
+                    ))
+                }
+                (Some("global"), [x]) => Ok(Exp::Global(parse_var(x)?)),
+                (Some("index"), [a, i]) => Ok(Exp::Index(parse_var(a)?, Box::new(Exp::parse(i)?))),
+                (
+                    Some(
+                        "begin" | "if" | "+" | "-" | "==" | "!=" | "bit_test" | "bit_set"
+                        | "bit_clear" | "carry" | "borrow" | "overflow" | "word" | "byte" | "set!"
+                        | "exp" | "global" | "set-global!" | "index" | "asm",
+                    ),
+                    _,
+                ) => malformed(sexp),
+                _ => Ok(Exp::Call(Box::new(Exp::parse(head)?), parse_all(rest)?)),
+            },
+        }
+    }
+}
+
+impl Parse for Stmt {
+    fn parse(sexp: &Sexp) -> Result<Stmt, String> {
+        match sexp.form() {
+            Some(("set!", [target, e])) => match target.form() {
+                Some(("index", [a, i])) => Ok(Stmt::SetIndex(
+                    parse_var(a)?,
+                    Box::new(Exp::parse(i)?),
+                    Box::new(Exp::parse(e)?),
+                )),
+                _ => Ok(Stmt::Let(parse_var(target)?, Box::new(Exp::parse(e)?))),
+            },
+            Some(("if", [test, conseq, alt])) => Ok(Stmt::If(
+                Box::new(Pred::parse(test)?),
+                parse_block(conseq)?,
+                parse_block(alt)?,
+            )),
+            Some(("begin", stmts)) => Ok(Stmt::Seq(parse_all(stmts)?)),
+            Some(("exp", [e])) => Ok(Stmt::Exp(Box::new(Exp::parse(e)?))),
+            Some(("set-global!", [x, e])) => {
+                Ok(Stmt::SetGlobal(parse_var(x)?, Box::new(Exp::parse(e)?)))
+            }
+            Some(("asm", _)) => Asm::parse(sexp).map(Stmt::Asm),
+            Some(("set!" | "set-global!" | "if" | "exp", _)) => malformed(sexp),
+            _ => Ok(Stmt::Exp(Box::new(Exp::parse(sexp)?))),
+        }
+    }
+}
+
+fn parse_block(sexp: &Sexp) -> Result<Vec<Stmt>, String> {
+    match sexp.form() {
+        Some(("begin", stmts)) => parse_all(stmts),
+        _ => expected("(begin stmt ...)", sexp),
+    }
+}
+
+impl Parse for Pred {
+    fn parse(sexp: &Sexp) -> Result<Pred, String> {
+        let items = match sexp {
+            Sexp::Atom(a) if a == "#t" => return Ok(Pred::True),
+            Sexp::Atom(a) if a == "#f" => return Ok(Pred::False),
+            Sexp::Atom(_) => return expected("a test", sexp),
+            Sexp::List(items) => items,
+        };
+        match items.as_slice() {
+            [] => expected("a test", sexp),
+            [head, rest @ ..] => match (head.atom(), rest) {
+                (Some("begin"), [stmts @ .., body]) => {
+                    Ok(Pred::Seq(parse_all(stmts)?, Box::new(Pred::parse(body)?)))
+                }
+                (Some("if"), [test, conseq, alt]) => Ok(Pred::If(
+                    Box::new(Pred::parse(test)?),
+                    Box::new(Pred::parse(conseq)?),
+                    Box::new(Pred::parse(alt)?),
+                )),
+                (
+                    Some("+" | "-" | "==" | "!=" | "carry" | "borrow" | "overflow" | "word"),
+                    [lhs, rhs],
+                ) => Ok(Pred::Relop(
+                    Box::new(Exp::parse(lhs)?),
+                    Op::parse(head)?,
+                    Box::new(Exp::parse(rhs)?),
+                )),
+                (Some("bit_test" | "bit_set" | "bit_clear" | "byte"), [x, n]) => {
+                    let op = Op::parse(head)?;
+                    let n = parse_position(&op, n)?;
+                    Ok(Pred::Relop(
+                        Box::new(Exp::parse(x)?),
+                        op,
+                        Box::new(Exp::Value(n)),
+                    ))
+                }
+                (
+                    Some(
+                        "begin" | "if" | "+" | "-" | "==" | "!=" | "bit_test" | "bit_set"
+                        | "bit_clear" | "carry" | "borrow" | "overflow" | "word" | "byte" | "set!"
+                        | "exp" | "global" | "set-global!" | "index" | "asm",
+                    ),
+                    _,
+                ) => malformed(sexp),
+                _ => Ok(Pred::Call(Box::new(Exp::parse(head)?), parse_all(rest)?)),
+            },
+        }
+    }
+}
+
+// `make_block` merges a block into the block it ends, so a `Seq` never directly
+// contains another `Seq`.
+impl Verify for Exp {
+    fn verify(&self) -> Result<(), String> {
+        match self {
+            Exp::Call(subject, args) => {
+                subject.verify()?;
+                args.iter().try_for_each(Exp::verify)
+            }
+            Exp::Seq(_, body) if matches!(**body, Exp::Seq(_, _)) => {
+                Err(format!("block ends in a block: {:?}", self))
+            }
+            Exp::Seq(stmts, body) => {
+                stmts.iter().try_for_each(Stmt::verify)?;
+                body.verify()
+            }
+            Exp::If(test, conseq, alt) => {
+                test.verify()?;
+                conseq.verify()?;
+                alt.verify()
+            }
+            Exp::Binop(lhs, _, rhs) => {
+                lhs.verify()?;
+                rhs.verify()
+            }
+            Exp::Index(_, i) => i.verify(),
+            Exp::Value(_) | Exp::Var(_) | Exp::Global(_) => Ok(()),
+        }
+    }
+}
+
+impl Verify for Stmt {
+    fn verify(&self) -> Result<(), String> {
+        match self {
+            Stmt::If(test, conseq, alt) => {
+                test.verify()?;
+                conseq.iter().try_for_each(Stmt::verify)?;
+                alt.iter().try_for_each(Stmt::verify)
+            }
+            Stmt::Seq(stmts) => stmts.iter().try_for_each(Stmt::verify),
+            Stmt::Let(_, e) | Stmt::SetGlobal(_, e) | Stmt::Exp(e) => e.verify(),
+            Stmt::SetIndex(_, i, e) => {
+                i.verify()?;
+                e.verify()
+            }
+            Stmt::Asm(_) => Ok(()),
+        }
+    }
+}
+
+impl Verify for Pred {
+    fn verify(&self) -> Result<(), String> {
+        match self {
+            Pred::Call(subject, args) => {
+                subject.verify()?;
+                args.iter().try_for_each(Exp::verify)
+            }
+            Pred::Relop(_, op, _) if !op.is_comparison() => {
+                Err(format!("arithmetic in test position: {:?}", self))
+            }
+            Pred::Relop(lhs, _, rhs) => {
+                lhs.verify()?;
+                rhs.verify()
+            }
+            Pred::Seq(_, body) if matches!(**body, Pred::Seq(_, _)) => {
+                Err(format!("block ends in a block: {:?}", self))
+            }
+            Pred::Seq(stmts, body) => {
+                stmts.iter().try_for_each(Stmt::verify)?;
+                body.verify()
+            }
+            Pred::If(test, conseq, alt) => {
+                test.verify()?;
+                conseq.verify()?;
+                alt.verify()
+            }
+            Pred::True | Pred::False => Ok(()),
+        }
+    }
+}
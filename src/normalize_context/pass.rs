@@ -2,21 +2,38 @@ use std::collections::BTreeMap;
 
 use crate::input;
 use crate::normalize_context::ast;
-use crate::shared::ast::{Op, Program, Value};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Func, Op, Program, Value};
+use crate::shared::names::Names;
 
-pub struct Pass {
-    counter: u32,
+pub struct Pass;
+
+impl Stage for Pass {
+    type Input = input::Exp;
+    type Output = ast::Exp;
+    const NAME: &'static str = "normalize_context";
+
+    fn run(program: Program<input::Exp>, _: &mut Names) -> Program<ast::Exp> {
+        Pass::run(program)
+    }
 }
 
 impl Pass {
     pub fn run(program: Program<input::Exp>) -> Program<ast::Exp> {
-        let Program { funcs } = program;
-        let mut pass = Pass { counter: 0 };
+        let Program { globals, funcs } = program;
+        let mut pass = Pass;
         let mut output_funcs = BTreeMap::new();
-        for (name, body) in funcs {
-            output_funcs.insert(name, pass.value(body));
+        for (name, Func { params, body }) in funcs {
+            output_funcs.insert(
+                name,
+                Func {
+                    params,
+                    body: pass.value(body),
+                },
+            );
         }
         Program {
+            globals,
             funcs: output_funcs,
         }
     }
@@ -24,71 +41,70 @@ impl Pass {
     fn value(&mut self, e: input::Exp) -> ast::Exp {
         match e {
             input::Exp::Call(subject, args) => ast::Exp::Call(
-                self.bvalue(subject),
+                self.bvalue(*subject),
                 args.into_iter().map(|arg| self.value(arg)).collect(),
             ),
             input::Exp::Seq(stmts, value) => {
                 let stmts = self.stmts(stmts);
                 make_block(stmts, self.value(*value))
             }
-            input::Exp::Binop(lhs, op, rhs) => match op {
-                Op::Add | Op::Sub => ast::Exp::Binop(self.bvalue(lhs), op, self.bvalue(rhs)),
-                Op::Eq | Op::Neq => ast::Exp::If(
-                    Box::new(ast::Pred::Relop(self.bvalue(lhs), op, self.bvalue(rhs))),
-                    Box::new(ast::Exp::Value(Value::True)),
-                    Box::new(ast::Exp::Value(Value::False)),
-                ),
-            },
+            // Comparisons, bit tests included, become tests.
+            input::Exp::Binop(lhs, op, rhs) if op.is_comparison() => ast::Exp::If(
+                Box::new(ast::Pred::Relop(self.bvalue(*lhs), op, self.bvalue(*rhs))),
+                Box::new(ast::Exp::Value(Value::True)),
+                Box::new(ast::Exp::Value(Value::False)),
+            ),
+            input::Exp::Binop(lhs, op, rhs) => {
+                ast::Exp::Binop(self.bvalue(*lhs), op, self.bvalue(*rhs))
+            }
             input::Exp::If(test, conseq, alt) => {
-                ast::Exp::If(self.bpred(test), self.bvalue(conseq), self.bvalue(alt))
+                ast::Exp::If(self.bpred(*test), self.bvalue(*conseq), self.bvalue(*alt))
             }
             input::Exp::Value(v) => ast::Exp::Value(v),
             input::Exp::Var(x) => ast::Exp::Var(x),
+            input::Exp::Global(x) => ast::Exp::Global(x),
+            input::Exp::Index(a, i) => ast::Exp::Index(a, self.bvalue(*i)),
+            input::Exp::Lambda(_, _) => unreachable!("lambda was not converted"),
         }
     }
 
-    fn bvalue(&mut self, e: Box<input::Exp>) -> Box<ast::Exp> {
-        Box::new(self.value(*e))
+    fn bvalue(&mut self, e: input::Exp) -> Box<ast::Exp> {
+        Box::new(self.value(e))
     }
 
     fn pred(&mut self, e: input::Exp) -> ast::Pred {
         match e {
-            // We didn't type-check, so scheme truthiness for now
-            e @ (input::Exp::Call(_, _) | input::Exp::Value(_) | input::Exp::Var(_)) => {
-                ast::Pred::If(
-                    Box::new(ast::Pred::Relop(
-                        Box::new(self.value(e)),
-                        Op::Eq,
-                        Box::new(ast::Exp::Value(Value::False)),
-                    )),
-                    Box::new(ast::Pred::False),
-                    Box::new(ast::Pred::True),
-                )
+            input::Exp::Binop(lhs, op, rhs) if op.is_comparison() => {
+                ast::Pred::Relop(self.bvalue(*lhs), op, self.bvalue(*rhs))
             }
+            // We didn't type-check, so scheme truthiness for now
+            e @ (input::Exp::Call(_, _)
+            | input::Exp::Binop(_, _, _)
+            | input::Exp::Value(_)
+            | input::Exp::Var(_)
+            | input::Exp::Global(_)
+            | input::Exp::Index(_, _)
+            | input::Exp::Lambda(_, _)) => ast::Pred::If(
+                Box::new(ast::Pred::Relop(
+                    Box::new(self.value(e)),
+                    Op::Eq,
+                    Box::new(ast::Exp::Value(Value::False)),
+                )),
+                Box::new(ast::Pred::False),
+                Box::new(ast::Pred::True),
+            ),
             input::Exp::Seq(stmts, value) => {
                 let stmts = self.stmts(stmts);
-                ast::Pred::Seq(stmts, self.bpred(value))
+                make_pred_block(stmts, self.pred(*value))
             }
-            input::Exp::Binop(lhs, op, rhs) => match &op {
-                Op::Add | Op::Sub => ast::Pred::If(
-                    Box::new(ast::Pred::Relop(
-                        Box::new(self.value(input::Exp::Binop(lhs, op, rhs))),
-                        Op::Eq,
-                        Box::new(ast::Exp::Value(Value::False)),
-                    )),
-                    Box::new(ast::Pred::False),
-                    Box::new(ast::Pred::True),
-                ),
-                Op::Eq | Op::Neq => ast::Pred::Relop(self.bvalue(lhs), op, self.bvalue(rhs)),
-            },
             input::Exp::If(test, conseq, alt) => {
-                ast::Pred::If(self.bpred(test), self.bpred(conseq), self.bpred(alt))
+                ast::Pred::If(self.bpred(*test), self.bpred(*conseq), self.bpred(*alt))
             }
         }
     }
 
-    fn bpred(&mut self, e: Box<input::Exp>) -> Box<ast::Pred> {
-        Box::new(self.pred(*e))
+    fn bpred(&mut self, e: input::Exp) -> Box<ast::Pred> {
+        Box::new(self.pred(e))
     }
 
     fn stmts(&mut self, ss: Vec<input::Stmt>) -> Vec<ast::Stmt> {
@@ -102,7 +118,13 @@ impl Pass {
     fn stmt(&mut self, block: &mut Vec<ast::Stmt>, s: input::Stmt) {
         match s {
             input::Stmt::Exp(e) => self.stmt_expr(block, *e),
-            input::Stmt::Let(x, e) => block.push(ast::Stmt::Let(x, self.bvalue(e))),
+            input::Stmt::Let(x, e) => block.push(ast::Stmt::Let(x, self.bvalue(*e))),
+            input::Stmt::SetGlobal(x, e) => block.push(ast::Stmt::SetGlobal(x, self.bvalue(*e))),
+            input::Stmt::SetIndex(a, i, e) => {
+                block.push(ast::Stmt::SetIndex(a, self.bvalue(*i), self.bvalue(*e)))
+            }
+            input::Stmt::Array(a, _) => unreachable!("local array {} was not hoisted", a),
+            input::Stmt::Asm(asm) => block.push(ast::Stmt::Asm(asm)),
         }
     }
 
@@ -126,9 +148,12 @@ impl Pass {
                 self.stmt_expr(&mut conseq_block, *conseq);
                 let mut alt_block = vec![];
                 self.stmt_expr(&mut alt_block, *alt);
-                block.push(ast::Stmt::If(self.bpred(test), conseq_block, alt_block));
+                block.push(ast::Stmt::If(self.bpred(*test), conseq_block, alt_block));
             }
-            input::Exp::Value(_) | input::Exp::Var(_) => (),
+            // Only the index can have effects; the read itself goes unused.
+            input::Exp::Index(_, i) => self.stmt_expr(block, *i),
+            input::Exp::Value(_) | input::Exp::Var(_) | input::Exp::Global(_) => (),
+            input::Exp::Lambda(_, _) => unreachable!("lambda was not converted"),
         }
     }
 }
@@ -137,13 +162,30 @@ fn make_block(block: Vec<ast::Stmt>, exp: ast::Exp) -> ast::Exp {
     match exp {
         exp if block.is_empty() => exp,
         ast::Exp::Seq(stmts, base) => {
-            let stmts = block.into_iter().chain(stmts.into_iter()).collect();
+            let stmts = block.into_iter().chain(stmts).collect();
             ast::Exp::Seq(stmts, base)
         }
         exp @ (ast::Exp::Call(_, _)
         | ast::Exp::If(_, _, _)
         | ast::Exp::Var(_)
+        | ast::Exp::Global(_)
+        | ast::Exp::Index(_, _)
         | ast::Exp::Value(_)
         | ast::Exp::Binop(_, _, _)) => ast::Exp::Seq(block, Box::new(exp)),
     }
 }
+
+fn make_pred_block(block: Vec<ast::Stmt>, pred: ast::Pred) -> ast::Pred {
+    match pred {
+        pred if block.is_empty() => pred,
+        ast::Pred::Seq(stmts, base) => {
+            let stmts = block.into_iter().chain(stmts).collect();
+            ast::Pred::Seq(stmts, base)
+        }
+        pred @ (ast::Pred::Call(_, _)
+        | ast::Pred::Relop(_, _, _)
+        | ast::Pred::If(_, _, _)
+        | ast::Pred::True
+        | ast::Pred::False) => ast::Pred::Seq(block, Box::new(pred)),
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::build_cfg::dominators::{predecessors, Dominators};
+use crate::convert_to_ssa::ast::{self, Label, Rhs, Stmt, Terminator};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Asm, Func, Op, Program, Triv, Value, Var};
+use crate::shared::names::Names;
+
+// Dominator-based value numbering over SSA. Walking the dominator tree with a scoped
+// table of the expressions computed so far finds every recomputation whose earlier
+// copy is guaranteed to have run; the later one is dropped and its uses renamed.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Binop(Var, Op, Triv),
+    Call(Var, Vec<Triv>),
+    Phi(Label, Vec<(Label, Triv)>),
+}
+
+pub struct Pass<'a> {
+    pure: &'a BTreeSet<Var>,
+    // The function's own variables, parameters included, which shadow functions.
+    locals: BTreeSet<Var>,
+    replace: BTreeMap<Var, Var>,
+}
+
+impl Stage for Pass<'_> {
+    type Input = ast::Cfg;
+    type Output = ast::Cfg;
+    const NAME: &'static str = "number_values";
+
+    fn run(program: Program<ast::Cfg>, _: &mut Names) -> Program<ast::Cfg> {
+        Pass::run(program)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<ast::Cfg>) -> Program<ast::Cfg> {
+        let pure = pure_funcs(&program);
+        let Program { globals, funcs } = program;
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let mut pass = Pass {
+                pure: &pure,
+                locals: locals(&params, &body),
+                replace: BTreeMap::new(),
+            };
+            output_funcs.insert(
+                name,
+                Func {
+                    params,
+                    body: pass.cfg(body),
+                },
+            );
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    fn cfg(&mut self, mut cfg: ast::Cfg) -> ast::Cfg {
+        let doms = Dominators::new(&cfg.successors());
+        self.visit(0, &mut cfg.blocks, &doms, BTreeMap::new());
+        let blocks = cfg
+            .blocks
+            .into_iter()
+            .map(|block| self.rename_block(block))
+            .collect();
+        merge_jumps(ast::Cfg { blocks }).remove_dead_definitions()
+    }
+
+    fn var(&self, x: &Var) -> Var {
+        let mut x = x;
+        while let Some(y) = self.replace.get(x) {
+            x = y;
+        }
+        x.clone()
+    }
+
+    fn triv(&self, t: &Triv) -> Triv {
+        match t {
+            Triv::Var(x) => Triv::Var(self.var(x)),
+            t => t.clone(),
+        }
+    }
+
+    fn visit(
+        &mut self,
+        label: Label,
+        blocks: &mut [ast::Block],
+        doms: &Dominators,
+        mut table: BTreeMap<Key, Var>,
+    ) {
+        let block = &mut blocks[label];
+
+        let phis = std::mem::take(&mut block.phis);
+        for phi in phis {
+            let args = phi
+                .args
+                .iter()
+                .map(|(pred, t)| (*pred, self.triv(t)))
+                .collect::<Vec<_>>();
+            // A phi whose incoming values are all the same variable is just a copy.
+            let mut sources = args
+                .iter()
+                .map(|(_, t)| t)
+                .filter(|t| **t != Triv::Var(phi.dest.clone()))
+                .collect::<BTreeSet<_>>();
+            if let (1, Some(Triv::Var(y))) = (sources.len(), sources.pop_first()) {
+                self.replace.insert(phi.dest, y.clone());
+                continue;
+            }
+            let key = Key::Phi(label, args);
+            match table.get(&key) {
+                Some(y) => {
+                    self.replace.insert(phi.dest, y.clone());
+                }
+                None => {
+                    table.insert(key, phi.dest.clone());
+                    block.phis.push(phi);
+                }
+            }
+        }
+
+        let stmts = std::mem::take(&mut block.stmts);
+        for stmt in stmts {
+            let (x, rhs) = match stmt {
+                Stmt::Let(x, rhs) => (x, rhs),
+                // Stores are renamed with everything else once the walk is done.
+                stmt @ (Stmt::SetGlobal(_, _) | Stmt::SetIndex(_, _, _) | Stmt::Asm(_)) => {
+                    block.stmts.push(stmt);
+                    continue;
+                }
+            };
+            match &rhs {
+                Rhs::Triv(Triv::Var(y)) => {
+                    let y = self.var(y);
+                    self.replace.insert(x, y);
+                }
+                Rhs::Binop(y, op, t) => {
+                    let (y, t) = (self.var(y), self.triv(t));
+                    let key = match (op, &t) {
+                        // Addition commutes, so both operand orders share a number.
+                        (Op::Add, Triv::Var(z)) if *z < y => {
+                            Key::Binop(z.clone(), op.clone(), Triv::Var(y))
+                        }
+                        _ => Key::Binop(y, op.clone(), t),
+                    };
+                    match table.get(&key) {
+                        Some(z) => {
+                            self.replace.insert(x, z.clone());
+                        }
+                        None => {
+                            table.insert(key, x.clone());
+                            block.stmts.push(Stmt::Let(x, rhs));
+                        }
+                    }
+                }
+                // A load is not numbered: a store or call between two loads of the
+                // same global or element can change what the second one reads.
+                Rhs::Triv(_) | Rhs::Global(_) | Rhs::Index(_, _) => {
+                    block.stmts.push(Stmt::Let(x, rhs))
+                }
+            }
+        }
+
+        if let Terminator::Call {
+            dest: Some(x),
+            subject,
+            args,
+            next,
+        } = &block.term
+        {
+            let callee = callee(subject, &self.locals);
+            if let Some(f) = callee.filter(|f| self.pure.contains(*f)) {
+                let key = Key::Call(f.clone(), args.iter().map(|arg| self.triv(arg)).collect());
+                match table.get(&key) {
+                    Some(y) => {
+                        self.replace.insert(x.clone(), y.clone());
+                        block.term = Terminator::Jump(*next);
+                    }
+                    None => {
+                        table.insert(key, x.clone());
+                    }
+                }
+            }
+        }
+
+        for &child in &doms.children[label] {
+            self.visit(child, blocks, doms, table.clone());
+        }
+    }
+
+    fn rename_block(&self, block: ast::Block) -> ast::Block {
+        let phis = block
+            .phis
+            .into_iter()
+            .map(|phi| ast::Phi {
+                dest: phi.dest,
+                args: phi
+                    .args
+                    .into_iter()
+                    .map(|(pred, t)| (pred, self.triv(&t)))
+                    .collect(),
+            })
+            .collect();
+        let stmts = block
+            .stmts
+            .into_iter()
+            .map(|stmt| match stmt {
+                Stmt::Let(x, rhs) => {
+                    let rhs = match rhs {
+                        Rhs::Triv(t) => Rhs::Triv(self.triv(&t)),
+                        Rhs::Binop(y, op, t) => Rhs::Binop(self.var(&y), op, self.triv(&t)),
+                        rhs @ Rhs::Global(_) => rhs,
+                        Rhs::Index(a, i) => Rhs::Index(a, self.triv(&i)),
+                    };
+                    Stmt::Let(x, rhs)
+                }
+                Stmt::SetGlobal(x, t) => Stmt::SetGlobal(x, self.triv(&t)),
+                Stmt::SetIndex(a, i, t) => Stmt::SetIndex(a, self.triv(&i), self.triv(&t)),
+                Stmt::Asm(Asm {
+                    outputs,
+                    inputs,
+                    body,
+                }) => Stmt::Asm(Asm {
+                    outputs,
+                    inputs: inputs.iter().map(|x| self.var(x)).collect(),
+                    body,
+                }),
+            })
+            .collect();
+        let term = match block.term {
+            term @ Terminator::Jump(_) => term,
+            Terminator::Branch(x, op, t, conseq, alt) => {
+                Terminator::Branch(self.var(&x), op, self.triv(&t), conseq, alt)
+            }
+            Terminator::Call {
+                dest,
+                subject,
+                args,
+                next,
+            } => Terminator::Call {
+                dest,
+                subject: self.triv(&subject),
+                args: args.iter().map(|arg| self.triv(arg)).collect(),
+                next,
+            },
+            Terminator::Return(t) => Terminator::Return(self.triv(&t)),
+        };
+        ast::Block { phis, stmts, term }
+    }
+}
+
+// A shared call leaves its block jumping straight to the old return point; splice
+// such a successor into its only predecessor.
+fn merge_jumps(cfg: ast::Cfg) -> ast::Cfg {
+    let mut blocks = cfg.blocks;
+    let succs = blocks
+        .iter()
+        .map(|block| block.term.successors())
+        .collect::<Vec<_>>();
+    let mut preds = predecessors(&succs);
+    for label in 0..blocks.len() {
+        while let Terminator::Jump(next) = blocks[label].term {
+            if next == 0 || next == label || preds[next].len() != 1 || !blocks[next].phis.is_empty()
+            {
+                break;
+            }
+            let stmts = std::mem::take(&mut blocks[next].stmts);
+            let term = std::mem::replace(&mut blocks[next].term, Terminator::Jump(next));
+            for succ in term.successors() {
+                for pred in preds[succ].iter_mut().filter(|pred| **pred == next) {
+                    *pred = label;
+                }
+                for phi in &mut blocks[succ].phis {
+                    for (pred, _) in phi.args.iter_mut().filter(|(pred, _)| *pred == next) {
+                        *pred = label;
+                    }
+                }
+            }
+            blocks[label].stmts.extend(stmts);
+            blocks[label].term = term;
+        }
+    }
+    ast::Cfg { blocks }.prune_unreachable()
+}
+
+// Functions whose calls can be shared: a function is pure unless it touches a global
+// or an array, runs inline assembly, or calls through something that is not a known
+// pure function. Computed optimistically so recursive functions like fib qualify.
+fn pure_funcs(program: &Program<ast::Cfg>) -> BTreeSet<Var> {
+    let mut pure = program.funcs.keys().cloned().collect::<BTreeSet<_>>();
+    let locals = program
+        .funcs
+        .iter()
+        .map(|(name, func)| (name, locals(&func.params, &func.body)))
+        .collect::<BTreeMap<_, _>>();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (name, func) in &program.funcs {
+            if !pure.contains(name) {
+                continue;
+            }
+            let impure = func.body.blocks.iter().any(|block| {
+                let globals = block.stmts.iter().any(|stmt| {
+                    matches!(
+                        stmt,
+                        Stmt::SetGlobal(_, _)
+                            | Stmt::SetIndex(_, _, _)
+                            | Stmt::Asm(_)
+                            | Stmt::Let(_, Rhs::Global(_) | Rhs::Index(_, _))
+                    )
+                });
+                let calls_impure = match &block.term {
+                    Terminator::Call { subject, .. } => {
+                        callee(subject, &locals[name]).is_none_or(|f| !pure.contains(f))
+                    }
+                    _ => false,
+                };
+                globals || calls_impure
+            });
+            if impure {
+                pure.remove(name);
+                changed = true;
+            }
+        }
+    }
+    pure
+}
+
+// The function a call subject names, if it is one: an address, or a name the calling
+// function does not use for a variable of its own.
+fn callee<'a>(subject: &'a Triv, locals: &BTreeSet<Var>) -> Option<&'a Var> {
+    match subject {
+        Triv::Var(f) if !locals.contains(f) => Some(f),
+        Triv::Value(Value::Func(f)) => Some(f),
+        _ => None,
+    }
+}
+
+fn locals(params: &[Var], cfg: &ast::Cfg) -> BTreeSet<Var> {
+    let mut locals = params.iter().cloned().collect::<BTreeSet<_>>();
+    for block in &cfg.blocks {
+        locals.extend(block.phis.iter().map(|phi| phi.dest.clone()));
+        for stmt in &block.stmts {
+            match stmt {
+                Stmt::Let(x, _) => {
+                    locals.insert(x.clone());
+                }
+                Stmt::Asm(asm) => locals.extend(asm.outputs.iter().cloned()),
+                Stmt::SetGlobal(_, _) | Stmt::SetIndex(_, _, _) => (),
+            }
+        }
+        if let Terminator::Call { dest: Some(x), .. } = &block.term {
+            locals.insert(x.clone());
+        }
+    }
+    locals
+}
@@ -0,0 +1,231 @@
+use std::time::{Duration, Instant};
+
+use crate::build_cfg::ast::Cfg;
+use crate::input::{self, Exp};
+use crate::shared::ast::{Program, Value};
+use crate::shared::names::Names;
+use crate::shared::read::{parse_all, read};
+use crate::shared::{Parse, ToDoc, Verify};
+use crate::{
+    build_cfg, convert_closures, convert_out_of_ssa, convert_to_ssa, evaluate_calls,
+    fold_constants, hoist_arrays, inline_functions, normalize_context, number_values,
+    propagate_constants, propagate_copies, rename_variables, simplify_values,
+};
+
+// What every pass looks like to the pipeline: a name to refer to it by on the
+// command line and a whole-program rewrite from one IR to another. Any name a pass
+// makes up comes from `names`, which the whole pipeline shares.
+pub trait Stage {
+    type Input;
+    type Output;
+    const NAME: &'static str;
+
+    fn run(program: Program<Self::Input>, names: &mut Names) -> Program<Self::Output>;
+}
+
+// How wide printed IR is allowed to get before forms are broken over lines.
+const WIDTH: usize = 80;
+
+#[derive(Debug, Default)]
+pub struct Options {
+    // The source file, which everything but fuzzing needs.
+    pub input: Option<String>,
+    // The pass whose output goes to stdout, one of `PASSES`.
+    pub emit: Option<String>,
+    // Names from `PASSES`, or `all`.
+    pub print_after: Vec<String>,
+    pub time_passes: bool,
+    // Check each pass's output is well formed and reads back the same after being
+    // printed. On by default in debug builds, where it costs little next to the rest.
+    pub verify: bool,
+    // Print each function's final CFG as a Graphviz graph instead of the listing.
+    pub dot: bool,
+    // Run the compiled `main` on these arguments and print what it returns instead
+    // of the listing.
+    pub run: Option<Vec<Value>>,
+    // Compile this many random programs instead of a source file.
+    pub fuzz: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+impl Options {
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options {
+            verify: cfg!(debug_assertions),
+            ..Options::default()
+        };
+        for arg in args {
+            if let Some(pass) = arg.strip_prefix("--emit=") {
+                if options.emit.is_some() {
+                    return Err("--emit given twice".to_string());
+                }
+                options.emit = Some(known_pass(pass)?);
+            } else if let Some(pass) = arg.strip_prefix("--print-after=") {
+                let pass = if pass == "all" {
+                    pass.to_string()
+                } else {
+                    known_pass(pass)?
+                };
+                options.print_after.push(pass);
+            } else if let Some(args) = arg.strip_prefix("--run=") {
+                let args = read(&args.replace(',', " ")).and_then(|sexps| parse_all(&sexps));
+                options.run =
+                    Some(args.map_err(|err| format!("bad arguments in `{}`: {}", arg, err))?);
+            } else if let Some(n) = arg.strip_prefix("--fuzz=") {
+                options.fuzz = Some(n.parse().map_err(|_| format!("bad count in `{}`", arg))?);
+            } else if let Some(n) = arg.strip_prefix("--seed=") {
+                options.seed = Some(n.parse().map_err(|_| format!("bad seed in `{}`", arg))?);
+            } else if arg == "--time-passes" {
+                options.time_passes = true;
+            } else if arg == "--verify" {
+                options.verify = true;
+            } else if arg == "--dot" {
+                options.dot = true;
+            } else if !arg.starts_with("--") && options.input.is_none() {
+                options.input = Some(arg);
+            } else {
+                return Err(format!("unknown option `{}`", arg));
+            }
+        }
+        Ok(options)
+    }
+
+    fn prints_after(&self, name: &str) -> bool {
+        self.print_after
+            .iter()
+            .any(|pass| pass == name || pass == "all")
+    }
+}
+
+fn known_pass(name: &str) -> Result<String, String> {
+    if PASSES.contains(&name) {
+        Ok(name.to_string())
+    } else {
+        Err(format!(
+            "no pass is called `{}`; they are {}",
+            name,
+            PASSES.join(", ")
+        ))
+    }
+}
+
+fn verify<P: Stage>(program: &Program<P::Output>)
+where
+    P::Output: Verify + ToDoc + Parse + PartialEq,
+{
+    for (name, func) in &program.funcs {
+        if let Err(err) = func.body.verify() {
+            panic!("{} left {} malformed: {}", P::NAME, name, err);
+        }
+    }
+    let mut text = Vec::new();
+    let _ = program.to_doc().render(WIDTH, &mut text);
+    match Program::read(&String::from_utf8_lossy(&text)) {
+        Ok(read) if read == *program => (),
+        Ok(_) => panic!("{} output reads back differently", P::NAME),
+        Err(err) => panic!("{} output does not read back: {}", P::NAME, err),
+    }
+}
+
+pub struct Pipeline<'a, Body> {
+    program: Program<Body>,
+    names: Names,
+    options: &'a Options,
+    timings: Vec<(&'static str, Duration)>,
+}
+
+impl<'a, Body> Pipeline<'a, Body> {
+    pub fn new(program: Program<Body>, names: Names, options: &'a Options) -> Pipeline<'a, Body> {
+        Pipeline {
+            program,
+            names,
+            options,
+            timings: vec![],
+        }
+    }
+
+    // Runs `P` and, with `verify`, checks its output is well formed and survives being
+    // printed and read back before anything else sees it.
+    pub fn then<P>(self) -> Pipeline<'a, P::Output>
+    where
+        P: Stage<Input = Body>,
+        P::Output: Verify + ToDoc + Parse + PartialEq,
+    {
+        let Pipeline {
+            program,
+            mut names,
+            options,
+            mut timings,
+        } = self;
+        let start = Instant::now();
+        let program = P::run(program, &mut names);
+        timings.push((P::NAME, start.elapsed()));
+        if options.verify {
+            verify::<P>(&program);
+        }
+        if options.prints_after(P::NAME) {
+            eprintln!(";; after {}", P::NAME);
+            let _ = program.to_doc().render(WIDTH, &mut std::io::stderr());
+            eprintln!();
+        }
+        if options.emit.as_deref() == Some(P::NAME) {
+            let _ = program.to_doc().render(WIDTH, &mut std::io::stdout());
+            println!();
+        }
+        Pipeline {
+            program,
+            names,
+            options,
+            timings,
+        }
+    }
+
+    pub fn finish(self) -> Program<Body> {
+        if self.options.time_passes {
+            let total = self.timings.iter().map(|(_, time)| *time).sum::<Duration>();
+            for (name, time) in &self.timings {
+                eprintln!("{:>12.3?}  {}", time, name);
+            }
+            eprintln!("{:>12.3?}  total", total);
+        }
+        self.program
+    }
+}
+
+// Every pass `compile` runs, in order.
+pub const PASSES: [&str; 14] = [
+    hoist_arrays::pass::Pass::NAME,
+    rename_variables::pass::Pass::NAME,
+    convert_closures::pass::Pass::NAME,
+    evaluate_calls::pass::Pass::NAME,
+    inline_functions::pass::Pass::NAME,
+    normalize_context::pass::Pass::NAME,
+    fold_constants::pass::Pass::NAME,
+    simplify_values::pass::Pass::NAME,
+    propagate_copies::pass::Pass::NAME,
+    build_cfg::pass::Pass::NAME,
+    convert_to_ssa::pass::Pass::NAME,
+    propagate_constants::pass::Pass::NAME,
+    number_values::pass::Pass::NAME,
+    convert_out_of_ssa::pass::Pass::NAME,
+];
+
+pub fn compile(program: Program<Exp>, options: &Options) -> Program<Cfg> {
+    let names = Names::new(input::names(&program));
+    Pipeline::new(program, names, options)
+        .then::<hoist_arrays::pass::Pass>()
+        .then::<rename_variables::pass::Pass>()
+        .then::<convert_closures::pass::Pass>()
+        .then::<evaluate_calls::pass::Pass>()
+        .then::<inline_functions::pass::Pass>()
+        .then::<normalize_context::pass::Pass>()
+        .then::<fold_constants::pass::Pass>()
+        .then::<simplify_values::pass::Pass>()
+        .then::<propagate_copies::pass::Pass>()
+        .then::<build_cfg::pass::Pass>()
+        .then::<convert_to_ssa::pass::Pass>()
+        .then::<propagate_constants::pass::Pass>()
+        .then::<number_values::pass::Pass>()
+        .then::<convert_out_of_ssa::pass::Pass>()
+        .finish()
+}
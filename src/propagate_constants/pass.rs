@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::convert_to_ssa::ast::{self, Label, Rhs, Stmt, Terminator};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Func, Global, Op, Program, Triv, Value, Var};
+use crate::shared::names::Names;
+
+// Conditional constant propagation over SSA (Wegman and Zadeck). Every variable
+// starts out optimistically undefined and only blocks found to be reachable are
+// evaluated, so a constant branch keeps its dead arm from spoiling the phis below it.
+#[derive(Debug, Clone, PartialEq)]
+enum Lattice {
+    Undefined,
+    Const(Value),
+    Varying,
+}
+
+pub struct Pass<'a> {
+    // Only tables are needed: their contents never change, so a constant index
+    // into one reads a constant.
+    globals: &'a BTreeMap<Var, Global>,
+    values: BTreeMap<Var, Lattice>,
+    reachable: BTreeSet<Label>,
+    edges: BTreeSet<(Label, Label)>,
+}
+
+impl Stage for Pass<'_> {
+    type Input = ast::Cfg;
+    type Output = ast::Cfg;
+    const NAME: &'static str = "propagate_constants";
+
+    fn run(program: Program<ast::Cfg>, _: &mut Names) -> Program<ast::Cfg> {
+        Pass::run(program)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<ast::Cfg>) -> Program<ast::Cfg> {
+        let Program { globals, funcs } = program;
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let mut pass = Pass {
+                globals: &globals,
+                values: BTreeMap::new(),
+                reachable: BTreeSet::from([0]),
+                edges: BTreeSet::new(),
+            };
+            pass.solve(&body);
+            output_funcs.insert(
+                name,
+                Func {
+                    params,
+                    body: pass.rewrite(body),
+                },
+            );
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    fn solve(&mut self, cfg: &ast::Cfg) {
+        for block in &cfg.blocks {
+            for phi in &block.phis {
+                self.values.insert(phi.dest.clone(), Lattice::Undefined);
+            }
+            for stmt in &block.stmts {
+                if let Stmt::Let(x, _) = stmt {
+                    self.values.insert(x.clone(), Lattice::Undefined);
+                }
+            }
+            if let Terminator::Call { dest: Some(x), .. } = &block.term {
+                self.values.insert(x.clone(), Lattice::Undefined);
+            }
+        }
+        // The lattice is three high, so this settles after a few sweeps.
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (label, block) in cfg.blocks.iter().enumerate() {
+                if !self.reachable.contains(&label) {
+                    continue;
+                }
+                for phi in &block.phis {
+                    let value = phi
+                        .args
+                        .iter()
+                        .filter(|(pred, _)| self.edges.contains(&(*pred, label)))
+                        .fold(Lattice::Undefined, |acc, (_, t)| meet(acc, self.triv(t)));
+                    changed |= self.set(&phi.dest, value);
+                }
+                for stmt in &block.stmts {
+                    if let Stmt::Let(x, rhs) = stmt {
+                        let value = match rhs {
+                            Rhs::Triv(t) => self.triv(t),
+                            Rhs::Binop(y, op, t) => arith(self.var(y), op, self.triv(t)),
+                            // Whatever was last stored, by this function or another.
+                            Rhs::Global(_) => Lattice::Varying,
+                            Rhs::Index(a, i) => self.element(a, i),
+                        };
+                        changed |= self.set(x, value);
+                    }
+                }
+                let targets = match &block.term {
+                    Terminator::Branch(x, op, t, conseq, alt) => {
+                        match (self.var(x), self.triv(t)) {
+                            (Lattice::Const(v), Lattice::Const(w)) => match op.holds(&v, &w) {
+                                Some(true) => vec![*conseq],
+                                Some(false) => vec![*alt],
+                                // Goes wrong at run time, so either way will do.
+                                None => vec![*conseq, *alt],
+                            },
+                            (Lattice::Undefined, _) | (_, Lattice::Undefined) => vec![],
+                            _ => vec![*conseq, *alt],
+                        }
+                    }
+                    Terminator::Call { dest, next, .. } => {
+                        if let Some(x) = dest {
+                            changed |= self.set(x, Lattice::Varying);
+                        }
+                        vec![*next]
+                    }
+                    term @ (Terminator::Jump(_) | Terminator::Return(_)) => term.successors(),
+                };
+                for target in targets {
+                    changed |= self.edges.insert((label, target));
+                    changed |= self.reachable.insert(target);
+                }
+            }
+        }
+    }
+
+    fn element(&self, a: &Var, i: &Triv) -> Lattice {
+        let bytes = match self.globals.get(a) {
+            Some(Global::Table(bytes)) => bytes,
+            _ => return Lattice::Varying,
+        };
+        match self.triv(i) {
+            Lattice::Undefined => Lattice::Undefined,
+            Lattice::Const(Value::Int(n)) if usize::from(n) < bytes.len() => {
+                Lattice::Const(Value::Int(bytes[usize::from(n)]))
+            }
+            // Past the end, or not a byte, goes wrong at run time as written.
+            _ => Lattice::Varying,
+        }
+    }
+
+    fn set(&mut self, x: &Var, value: Lattice) -> bool {
+        let old = self.values.insert(x.clone(), value.clone());
+        old != Some(value)
+    }
+
+    fn var(&self, x: &Var) -> Lattice {
+        // Parameters and function names are not defined here and could be anything.
+        self.values.get(x).cloned().unwrap_or(Lattice::Varying)
+    }
+
+    fn triv(&self, t: &Triv) -> Lattice {
+        match t {
+            Triv::Value(v) => Lattice::Const(v.clone()),
+            Triv::Var(x) => self.var(x),
+            Triv::Return => Lattice::Varying,
+        }
+    }
+
+    fn constant(&self, x: &Var) -> Option<Value> {
+        match self.values.get(x) {
+            Some(Lattice::Const(v)) => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    fn replace(&self, t: Triv) -> Triv {
+        match t {
+            Triv::Var(x) => match self.constant(&x) {
+                Some(v) => Triv::Value(v),
+                None => Triv::Var(x),
+            },
+            t => t,
+        }
+    }
+
+    fn rewrite(&self, cfg: ast::Cfg) -> ast::Cfg {
+        let blocks = cfg
+            .blocks
+            .into_iter()
+            .enumerate()
+            .map(|(label, block)| {
+                if !self.reachable.contains(&label) {
+                    // Left in place so labels stay valid; pruned below.
+                    return ast::Block {
+                        phis: vec![],
+                        stmts: vec![],
+                        term: Terminator::Return(Triv::Value(Value::False)),
+                    };
+                }
+                let mut phis = vec![];
+                let mut stmts = vec![];
+                for phi in block.phis {
+                    match self.constant(&phi.dest) {
+                        Some(v) => stmts.push(Stmt::Let(phi.dest, Rhs::Triv(Triv::Value(v)))),
+                        None => phis.push(ast::Phi {
+                            dest: phi.dest,
+                            args: phi
+                                .args
+                                .into_iter()
+                                .filter(|(pred, _)| self.edges.contains(&(*pred, label)))
+                                .map(|(pred, t)| (pred, self.replace(t)))
+                                .collect(),
+                        }),
+                    }
+                }
+                for stmt in block.stmts {
+                    stmts.push(match stmt {
+                        Stmt::Let(x, rhs) => {
+                            let rhs = match self.constant(&x) {
+                                Some(v) => Rhs::Triv(Triv::Value(v)),
+                                None => match rhs {
+                                    Rhs::Triv(t) => Rhs::Triv(self.replace(t)),
+                                    Rhs::Binop(y, op, t) => Rhs::Binop(y, op, self.replace(t)),
+                                    rhs @ Rhs::Global(_) => rhs,
+                                    Rhs::Index(a, i) => Rhs::Index(a, self.replace(i)),
+                                },
+                            };
+                            Stmt::Let(x, rhs)
+                        }
+                        Stmt::SetGlobal(x, t) => Stmt::SetGlobal(x, self.replace(t)),
+                        Stmt::SetIndex(a, i, t) => {
+                            Stmt::SetIndex(a, self.replace(i), self.replace(t))
+                        }
+                        // Operands have to stay variables; its outputs are never
+                        // in `values`, so they read as varying.
+                        stmt @ Stmt::Asm(_) => stmt,
+                    });
+                }
+                let term = match block.term {
+                    Terminator::Branch(x, op, t, conseq, alt) => {
+                        match (
+                            self.edges.contains(&(label, conseq)),
+                            self.edges.contains(&(label, alt)),
+                        ) {
+                            (true, false) => Terminator::Jump(conseq),
+                            (false, true) => Terminator::Jump(alt),
+                            _ => Terminator::Branch(x, op, self.replace(t), conseq, alt),
+                        }
+                    }
+                    Terminator::Call {
+                        dest,
+                        subject,
+                        args,
+                        next,
+                    } => Terminator::Call {
+                        dest,
+                        subject,
+                        args: args.into_iter().map(|arg| self.replace(arg)).collect(),
+                        next,
+                    },
+                    Terminator::Return(t) => Terminator::Return(self.replace(t)),
+                    term @ Terminator::Jump(_) => term,
+                };
+                ast::Block { phis, stmts, term }
+            })
+            .collect();
+        ast::Cfg { blocks }
+            .prune_unreachable()
+            .remove_dead_definitions()
+    }
+}
+
+fn meet(a: Lattice, b: Lattice) -> Lattice {
+    match (a, b) {
+        (Lattice::Undefined, x) | (x, Lattice::Undefined) => x,
+        (Lattice::Const(v), Lattice::Const(w)) if v == w => Lattice::Const(v),
+        _ => Lattice::Varying,
+    }
+}
+
+fn arith(lhs: Lattice, op: &Op, rhs: Lattice) -> Lattice {
+    match (lhs, rhs) {
+        // Goes wrong at run time if it does not apply, as written.
+        (Lattice::Const(v), Lattice::Const(w)) => match op.apply(&v, &w) {
+            Some(v) => Lattice::Const(v),
+            None => Lattice::Varying,
+        },
+        (Lattice::Undefined, _) | (_, Lattice::Undefined) => Lattice::Undefined,
+        _ => Lattice::Varying,
+    }
+}
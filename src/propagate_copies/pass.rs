@@ -0,0 +1,540 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::pipeline::Stage;
+use crate::shared::ast::{Asm, Func, Program, Triv, Var};
+use crate::shared::names::Names;
+use crate::simplify_values::ast;
+
+pub struct Pass {
+    copies: BTreeMap<Var, Triv>,
+}
+
+impl Stage for Pass {
+    type Input = ast::Exp;
+    type Output = ast::Exp;
+    const NAME: &'static str = "propagate_copies";
+
+    fn run(program: Program<ast::Exp>, _: &mut Names) -> Program<ast::Exp> {
+        Pass::run(program)
+    }
+}
+
+impl Pass {
+    pub fn run(program: Program<ast::Exp>) -> Program<ast::Exp> {
+        let Program { globals, funcs } = program;
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let mut pass = Pass {
+                copies: BTreeMap::new(),
+            };
+            let mut body = pass.exp(body);
+            loop {
+                let mut sweep = Sweep {
+                    uses: count_uses(&body),
+                    changed: false,
+                };
+                body = sweep.exp(body);
+                if !sweep.changed {
+                    break;
+                }
+            }
+            output_funcs.insert(name, Func { params, body });
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    fn exp(&mut self, e: ast::Exp) -> ast::Exp {
+        match e {
+            ast::Exp::Call(subject, args) => ast::Exp::Call(
+                self.triv(subject),
+                args.into_iter().map(|arg| self.triv(arg)).collect(),
+            ),
+            ast::Exp::Binop(lhs, op, rhs) => ast::Exp::Binop(self.var(lhs), op, self.triv(rhs)),
+            ast::Exp::Triv(t) => ast::Exp::Triv(self.triv(t)),
+            ast::Exp::Seq(stmts, body) => {
+                let saved = self.copies.clone();
+                let stmts = self.stmts(stmts);
+                let body = self.bexp(*body);
+                self.leave_scope(saved, &stmts);
+                ast::Exp::Seq(stmts, body)
+            }
+            ast::Exp::If(test, conseq, alt) => {
+                let test = self.bpred(*test);
+                let conseq = self.branch(|pass| pass.bexp(*conseq));
+                let alt = self.branch(|pass| pass.bexp(*alt));
+                ast::Exp::If(test, conseq, alt)
+            }
+            ast::Exp::Global(x) => ast::Exp::Global(x),
+            ast::Exp::Index(a, i) => ast::Exp::Index(a, self.triv(i)),
+        }
+    }
+
+    fn bexp(&mut self, e: ast::Exp) -> Box<ast::Exp> {
+        Box::new(self.exp(e))
+    }
+
+    fn pred(&mut self, p: ast::Pred) -> ast::Pred {
+        match p {
+            ast::Pred::Call(subject, args) => ast::Pred::Call(
+                self.triv(subject),
+                args.into_iter().map(|arg| self.triv(arg)).collect(),
+            ),
+            ast::Pred::Relop(lhs, op, rhs) => ast::Pred::Relop(self.var(lhs), op, self.triv(rhs)),
+            ast::Pred::Triv(t) => ast::Pred::Triv(self.triv(t)),
+            ast::Pred::Seq(stmts, body) => {
+                let saved = self.copies.clone();
+                let stmts = self.stmts(stmts);
+                let body = self.bpred(*body);
+                self.leave_scope(saved, &stmts);
+                ast::Pred::Seq(stmts, body)
+            }
+            ast::Pred::If(test, conseq, alt) => {
+                let test = self.bpred(*test);
+                let conseq = self.branch(|pass| pass.bpred(*conseq));
+                let alt = self.branch(|pass| pass.bpred(*alt));
+                ast::Pred::If(test, conseq, alt)
+            }
+            ast::Pred::True => ast::Pred::True,
+            ast::Pred::False => ast::Pred::False,
+        }
+    }
+
+    fn bpred(&mut self, p: ast::Pred) -> Box<ast::Pred> {
+        Box::new(self.pred(p))
+    }
+
+    fn stmts(&mut self, ss: Vec<ast::Stmt>) -> Vec<ast::Stmt> {
+        ss.into_iter().map(|s| self.stmt(s)).collect()
+    }
+
+    fn stmt(&mut self, s: ast::Stmt) -> ast::Stmt {
+        match s {
+            ast::Stmt::If(test, conseq, alt) => {
+                let test = self.bpred(*test);
+                let conseq = self.branch(|pass| pass.stmts(conseq));
+                let alt = self.branch(|pass| pass.stmts(alt));
+                for x in bound_vars(&conseq).into_iter().chain(bound_vars(&alt)) {
+                    self.kill(&x);
+                }
+                ast::Stmt::If(test, conseq, alt)
+            }
+            ast::Stmt::Seq(stmts) => ast::Stmt::Seq(self.stmts(stmts)),
+            ast::Stmt::Let(x, rhs) => {
+                let rhs = self.bexp(*rhs);
+                self.kill(&x);
+                match &*rhs {
+                    ast::Exp::Triv(Triv::Var(y)) if *y == x => (),
+                    ast::Exp::Triv(t @ (Triv::Var(_) | Triv::Value(_))) => {
+                        self.copies.insert(x.clone(), t.clone());
+                    }
+                    _ => (),
+                }
+                ast::Stmt::Let(x, rhs)
+            }
+            ast::Stmt::SetGlobal(x, t) => ast::Stmt::SetGlobal(x, self.triv(t)),
+            ast::Stmt::SetIndex(a, i, t) => ast::Stmt::SetIndex(a, self.triv(i), self.triv(t)),
+            // The body only names its operands by position, so inputs can be renamed
+            // like any other use.
+            ast::Stmt::Asm(Asm {
+                outputs,
+                inputs,
+                body,
+            }) => {
+                let inputs = inputs.into_iter().map(|x| self.var(x)).collect();
+                for x in &outputs {
+                    self.kill(x);
+                }
+                ast::Stmt::Asm(Asm {
+                    outputs,
+                    inputs,
+                    body,
+                })
+            }
+            ast::Stmt::Exp(e) => ast::Stmt::Exp(self.bexp(*e)),
+        }
+    }
+
+    fn triv(&self, t: Triv) -> Triv {
+        match t {
+            Triv::Var(x) => self.copies.get(&x).cloned().unwrap_or(Triv::Var(x)),
+            t @ (Triv::Value(_) | Triv::Return) => t,
+        }
+    }
+
+    // Binop and Relop need a variable on the left, so only variable copies apply there.
+    fn var(&self, x: Var) -> Var {
+        match self.copies.get(&x) {
+            Some(Triv::Var(y)) => y.clone(),
+            _ => x,
+        }
+    }
+
+    // Forgets `x` and every copy that reads it, since `x` was just reassigned.
+    fn kill(&mut self, x: &Var) {
+        self.copies.remove(x);
+        self.copies
+            .retain(|_, t| !matches!(t, Triv::Var(y) if y == x));
+    }
+
+    fn branch<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let saved = self.copies.clone();
+        let result = f(self);
+        self.copies = saved;
+        result
+    }
+
+    fn leave_scope(&mut self, saved: BTreeMap<Var, Triv>, stmts: &[ast::Stmt]) {
+        self.copies = saved;
+        for x in bound_vars(stmts) {
+            self.kill(&x);
+        }
+    }
+}
+
+// Removes lets whose variable is never read and folds a temporary into the single
+// let or block result that reads it. Use counts are taken before the sweep, so they
+// only ever overestimate; the driver repeats the sweep until nothing changes.
+struct Sweep {
+    uses: BTreeMap<Var, usize>,
+    changed: bool,
+}
+
+impl Sweep {
+    fn uses(&self, x: &Var) -> usize {
+        self.uses.get(x).copied().unwrap_or(0)
+    }
+
+    fn exp(&mut self, e: ast::Exp) -> ast::Exp {
+        match e {
+            ast::Exp::Seq(stmts, body) => {
+                let mut block = self.stmts(stmts);
+                let body = self.exp(*body);
+                match body {
+                    ast::Exp::Triv(Triv::Var(t)) if self.uses(&t) == 1 => match block.pop() {
+                        Some(ast::Stmt::Let(x, rhs)) if x == t => {
+                            self.changed = true;
+                            make_block(block, *rhs)
+                        }
+                        last => {
+                            block.extend(last);
+                            make_block(block, ast::Exp::Triv(Triv::Var(t)))
+                        }
+                    },
+                    body => make_block(block, body),
+                }
+            }
+            ast::Exp::If(test, conseq, alt) => ast::Exp::If(
+                Box::new(self.pred(*test)),
+                Box::new(self.exp(*conseq)),
+                Box::new(self.exp(*alt)),
+            ),
+            e @ (ast::Exp::Call(_, _)
+            | ast::Exp::Binop(_, _, _)
+            | ast::Exp::Triv(_)
+            | ast::Exp::Global(_)
+            | ast::Exp::Index(_, _)) => e,
+        }
+    }
+
+    fn pred(&mut self, p: ast::Pred) -> ast::Pred {
+        match p {
+            ast::Pred::Seq(stmts, body) => {
+                let block = self.stmts(stmts);
+                make_pred_block(block, self.pred(*body))
+            }
+            ast::Pred::If(test, conseq, alt) => ast::Pred::If(
+                Box::new(self.pred(*test)),
+                Box::new(self.pred(*conseq)),
+                Box::new(self.pred(*alt)),
+            ),
+            p @ (ast::Pred::Call(_, _)
+            | ast::Pred::Relop(_, _, _)
+            | ast::Pred::Triv(_)
+            | ast::Pred::True
+            | ast::Pred::False) => p,
+        }
+    }
+
+    fn stmts(&mut self, ss: Vec<ast::Stmt>) -> Vec<ast::Stmt> {
+        let mut block = vec![];
+        for s in ss {
+            self.stmt(&mut block, s);
+        }
+        block
+    }
+
+    // pushes whatever survives of `s` onto the block
+    fn stmt(&mut self, block: &mut Vec<ast::Stmt>, s: ast::Stmt) {
+        match s {
+            ast::Stmt::If(test, conseq, alt) => {
+                let test = self.pred(*test);
+                let conseq = self.stmts(conseq);
+                let alt = self.stmts(alt);
+                block.push(ast::Stmt::If(Box::new(test), conseq, alt));
+            }
+            ast::Stmt::Seq(stmts) => {
+                for s in stmts {
+                    self.stmt(block, s);
+                }
+            }
+            ast::Stmt::Let(x, rhs) => {
+                let rhs = self.exp(*rhs);
+                if self.uses(&x) == 0 {
+                    self.changed = true;
+                    if !pure_exp(&rhs) {
+                        block.push(ast::Stmt::Exp(Box::new(rhs)));
+                    }
+                    return;
+                }
+                match rhs {
+                    ast::Exp::Triv(Triv::Var(t)) if self.uses(&t) == 1 => match block.pop() {
+                        Some(ast::Stmt::Let(y, def)) if y == t => {
+                            self.changed = true;
+                            block.push(ast::Stmt::Let(x, def));
+                        }
+                        last => {
+                            block.extend(last);
+                            block.push(ast::Stmt::Let(x, Box::new(ast::Exp::Triv(Triv::Var(t)))));
+                        }
+                    },
+                    rhs => block.push(ast::Stmt::Let(x, Box::new(rhs))),
+                }
+            }
+            s @ (ast::Stmt::SetGlobal(_, _) | ast::Stmt::SetIndex(_, _, _) | ast::Stmt::Asm(_)) => {
+                block.push(s)
+            }
+            ast::Stmt::Exp(e) => {
+                let e = self.exp(*e);
+                if pure_exp(&e) {
+                    self.changed = true;
+                } else {
+                    block.push(ast::Stmt::Exp(Box::new(e)));
+                }
+            }
+        }
+    }
+}
+
+// Whether `e` can be dropped when its value is unused: no calls, and no lets that
+// might be read after it. Reading a global or an array is fine; only storing to one
+// is an effect.
+fn pure_exp(e: &ast::Exp) -> bool {
+    match e {
+        ast::Exp::Call(_, _) => false,
+        ast::Exp::Binop(_, _, _)
+        | ast::Exp::Triv(_)
+        | ast::Exp::Global(_)
+        | ast::Exp::Index(_, _) => true,
+        ast::Exp::Seq(stmts, body) => stmts.is_empty() && pure_exp(body),
+        ast::Exp::If(test, conseq, alt) => pure_pred(test) && pure_exp(conseq) && pure_exp(alt),
+    }
+}
+
+fn pure_pred(p: &ast::Pred) -> bool {
+    match p {
+        ast::Pred::Call(_, _) => false,
+        ast::Pred::Relop(_, _, _) | ast::Pred::Triv(_) | ast::Pred::True | ast::Pred::False => true,
+        ast::Pred::Seq(stmts, body) => stmts.is_empty() && pure_pred(body),
+        ast::Pred::If(test, conseq, alt) => pure_pred(test) && pure_pred(conseq) && pure_pred(alt),
+    }
+}
+
+fn count_uses(e: &ast::Exp) -> BTreeMap<Var, usize> {
+    let mut uses = BTreeMap::new();
+    exp_uses(&mut uses, e);
+    uses
+}
+
+fn use_var(uses: &mut BTreeMap<Var, usize>, x: &Var) {
+    *uses.entry(x.clone()).or_insert(0) += 1;
+}
+
+fn triv_uses(uses: &mut BTreeMap<Var, usize>, t: &Triv) {
+    if let Triv::Var(x) = t {
+        use_var(uses, x);
+    }
+}
+
+fn exp_uses(uses: &mut BTreeMap<Var, usize>, e: &ast::Exp) {
+    match e {
+        ast::Exp::Call(subject, args) => {
+            triv_uses(uses, subject);
+            for arg in args {
+                triv_uses(uses, arg);
+            }
+        }
+        ast::Exp::Binop(lhs, _, rhs) => {
+            use_var(uses, lhs);
+            triv_uses(uses, rhs);
+        }
+        ast::Exp::Triv(t) => triv_uses(uses, t),
+        ast::Exp::Seq(stmts, body) => {
+            for s in stmts {
+                stmt_uses(uses, s);
+            }
+            exp_uses(uses, body);
+        }
+        ast::Exp::If(test, conseq, alt) => {
+            pred_uses(uses, test);
+            exp_uses(uses, conseq);
+            exp_uses(uses, alt);
+        }
+        ast::Exp::Index(_, i) => triv_uses(uses, i),
+        ast::Exp::Global(_) => (),
+    }
+}
+
+fn pred_uses(uses: &mut BTreeMap<Var, usize>, p: &ast::Pred) {
+    match p {
+        ast::Pred::Call(subject, args) => {
+            triv_uses(uses, subject);
+            for arg in args {
+                triv_uses(uses, arg);
+            }
+        }
+        ast::Pred::Relop(lhs, _, rhs) => {
+            use_var(uses, lhs);
+            triv_uses(uses, rhs);
+        }
+        ast::Pred::Triv(t) => triv_uses(uses, t),
+        ast::Pred::Seq(stmts, body) => {
+            for s in stmts {
+                stmt_uses(uses, s);
+            }
+            pred_uses(uses, body);
+        }
+        ast::Pred::If(test, conseq, alt) => {
+            pred_uses(uses, test);
+            pred_uses(uses, conseq);
+            pred_uses(uses, alt);
+        }
+        ast::Pred::True | ast::Pred::False => (),
+    }
+}
+
+fn stmt_uses(uses: &mut BTreeMap<Var, usize>, s: &ast::Stmt) {
+    match s {
+        ast::Stmt::If(test, conseq, alt) => {
+            pred_uses(uses, test);
+            for s in conseq.iter().chain(alt) {
+                stmt_uses(uses, s);
+            }
+        }
+        ast::Stmt::Seq(stmts) => {
+            for s in stmts {
+                stmt_uses(uses, s);
+            }
+        }
+        ast::Stmt::Let(_, rhs) => exp_uses(uses, rhs),
+        ast::Stmt::SetGlobal(_, t) => triv_uses(uses, t),
+        ast::Stmt::SetIndex(_, i, t) => {
+            triv_uses(uses, i);
+            triv_uses(uses, t);
+        }
+        ast::Stmt::Asm(asm) => {
+            for x in &asm.inputs {
+                use_var(uses, x);
+            }
+        }
+        ast::Stmt::Exp(e) => exp_uses(uses, e),
+    }
+}
+
+fn bound_vars(stmts: &[ast::Stmt]) -> BTreeSet<Var> {
+    let mut vars = BTreeSet::new();
+    for s in stmts {
+        stmt_bound_vars(&mut vars, s);
+    }
+    vars
+}
+
+fn stmt_bound_vars(vars: &mut BTreeSet<Var>, s: &ast::Stmt) {
+    match s {
+        ast::Stmt::If(test, conseq, alt) => {
+            pred_bound_vars(vars, test);
+            vars.extend(bound_vars(conseq));
+            vars.extend(bound_vars(alt));
+        }
+        ast::Stmt::Seq(stmts) => vars.extend(bound_vars(stmts)),
+        ast::Stmt::Let(x, rhs) => {
+            vars.insert(x.clone());
+            exp_bound_vars(vars, rhs);
+        }
+        ast::Stmt::SetGlobal(_, _) | ast::Stmt::SetIndex(_, _, _) => (),
+        ast::Stmt::Asm(asm) => vars.extend(asm.outputs.iter().cloned()),
+        ast::Stmt::Exp(e) => exp_bound_vars(vars, e),
+    }
+}
+
+fn exp_bound_vars(vars: &mut BTreeSet<Var>, e: &ast::Exp) {
+    match e {
+        ast::Exp::Seq(stmts, body) => {
+            vars.extend(bound_vars(stmts));
+            exp_bound_vars(vars, body);
+        }
+        ast::Exp::If(test, conseq, alt) => {
+            pred_bound_vars(vars, test);
+            exp_bound_vars(vars, conseq);
+            exp_bound_vars(vars, alt);
+        }
+        ast::Exp::Call(_, _)
+        | ast::Exp::Binop(_, _, _)
+        | ast::Exp::Triv(_)
+        | ast::Exp::Global(_)
+        | ast::Exp::Index(_, _) => (),
+    }
+}
+
+fn pred_bound_vars(vars: &mut BTreeSet<Var>, p: &ast::Pred) {
+    match p {
+        ast::Pred::Seq(stmts, body) => {
+            vars.extend(bound_vars(stmts));
+            pred_bound_vars(vars, body);
+        }
+        ast::Pred::If(test, conseq, alt) => {
+            pred_bound_vars(vars, test);
+            pred_bound_vars(vars, conseq);
+            pred_bound_vars(vars, alt);
+        }
+        ast::Pred::Call(_, _)
+        | ast::Pred::Relop(_, _, _)
+        | ast::Pred::Triv(_)
+        | ast::Pred::True
+        | ast::Pred::False => (),
+    }
+}
+
+fn make_block(block: Vec<ast::Stmt>, exp: ast::Exp) -> ast::Exp {
+    match exp {
+        exp if block.is_empty() => exp,
+        ast::Exp::Seq(stmts, base) => {
+            let stmts = block.into_iter().chain(stmts).collect();
+            make_block(stmts, *base)
+        }
+        exp @ (ast::Exp::Call(_, _)
+        | ast::Exp::If(_, _, _)
+        | ast::Exp::Triv(_)
+        | ast::Exp::Binop(_, _, _)
+        | ast::Exp::Global(_)
+        | ast::Exp::Index(_, _)) => ast::Exp::Seq(block, Box::new(exp)),
+    }
+}
+
+fn make_pred_block(block: Vec<ast::Stmt>, exp: ast::Pred) -> ast::Pred {
+    match exp {
+        exp if block.is_empty() => exp,
+        ast::Pred::Seq(stmts, base) => {
+            let stmts = block.into_iter().chain(stmts).collect();
+            make_pred_block(stmts, *base)
+        }
+        exp @ (ast::Pred::Call(_, _)
+        | ast::Pred::If(_, _, _)
+        | ast::Pred::Triv(_)
+        | ast::Pred::False
+        | ast::Pred::True
+        | ast::Pred::Relop(_, _, _)) => ast::Pred::Seq(block, Box::new(exp)),
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::input::{Exp, Stmt};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Asm, Func, Program, Var};
+use crate::shared::names::Names;
+
+// `set!` binds a variable for the rest of its block, but everything after this pass
+// treats one as an assignment to a variable the whole function shares. Giving every
+// binding a name of its own makes the two agree: a `set!` inside an `if` no longer
+// changes what a same-named variable outside it holds. The first binding of a name
+// keeps it unless a function has it too; the rest become `x.1` and so on.
+pub struct Pass<'a> {
+    funcs: BTreeSet<Var>,
+    // The names bound so far in this function.
+    bound: BTreeSet<Var>,
+    names: &'a mut Names,
+}
+
+impl Stage for Pass<'_> {
+    type Input = Exp;
+    type Output = Exp;
+    const NAME: &'static str = "rename_variables";
+
+    fn run(program: Program<Exp>, names: &mut Names) -> Program<Exp> {
+        Pass::run(program, names)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<Exp>, names: &mut Names) -> Program<Exp> {
+        let Program { globals, funcs } = program;
+        let func_names = funcs.keys().cloned().collect::<BTreeSet<_>>();
+        let mut output_funcs = BTreeMap::new();
+        for (name, Func { params, body }) in funcs {
+            let mut pass = Pass {
+                funcs: func_names.clone(),
+                bound: BTreeSet::new(),
+                names,
+            };
+            let mut scope = BTreeMap::new();
+            let params = params
+                .into_iter()
+                .map(|x| pass.bind(x, &mut scope))
+                .collect();
+            let body = pass.exp(body, &scope);
+            output_funcs.insert(name, Func { params, body });
+        }
+        Program {
+            globals,
+            funcs: output_funcs,
+        }
+    }
+
+    // `scope` maps each variable in scope to the name its binding got.
+    fn exp(&mut self, e: Exp, scope: &BTreeMap<Var, Var>) -> Exp {
+        match e {
+            Exp::Call(subject, args) => Exp::Call(
+                self.bexp(*subject, scope),
+                args.into_iter().map(|arg| self.exp(arg, scope)).collect(),
+            ),
+            Exp::Seq(stmts, body) => {
+                let mut scope = scope.clone();
+                let stmts = stmts
+                    .into_iter()
+                    .map(|stmt| match stmt {
+                        Stmt::Exp(e) => Stmt::Exp(self.bexp(*e, &scope)),
+                        // The value is computed before the new binding exists.
+                        Stmt::Let(x, e) => {
+                            let e = self.bexp(*e, &scope);
+                            Stmt::Let(self.bind(x, &mut scope), e)
+                        }
+                        Stmt::SetGlobal(x, e) => Stmt::SetGlobal(x, self.bexp(*e, &scope)),
+                        Stmt::SetIndex(a, i, e) => {
+                            Stmt::SetIndex(a, self.bexp(*i, &scope), self.bexp(*e, &scope))
+                        }
+                        s @ Stmt::Array(_, _) => s,
+                        Stmt::Asm(Asm {
+                            outputs,
+                            inputs,
+                            body,
+                        }) => {
+                            let inputs = inputs.into_iter().map(|x| resolve(&scope, x)).collect();
+                            let outputs = outputs
+                                .into_iter()
+                                .map(|x| self.bind(x, &mut scope))
+                                .collect();
+                            Stmt::Asm(Asm {
+                                outputs,
+                                inputs,
+                                body,
+                            })
+                        }
+                    })
+                    .collect();
+                Exp::Seq(stmts, self.bexp(*body, &scope))
+            }
+            Exp::Binop(lhs, op, rhs) => {
+                Exp::Binop(self.bexp(*lhs, scope), op, self.bexp(*rhs, scope))
+            }
+            Exp::If(test, conseq, alt) => Exp::If(
+                self.bexp(*test, scope),
+                self.bexp(*conseq, scope),
+                self.bexp(*alt, scope),
+            ),
+            Exp::Index(a, i) => Exp::Index(a, self.bexp(*i, scope)),
+            Exp::Lambda(params, body) => {
+                let mut scope = scope.clone();
+                let params = params
+                    .into_iter()
+                    .map(|x| self.bind(x, &mut scope))
+                    .collect();
+                Exp::Lambda(params, self.bexp(*body, &scope))
+            }
+            Exp::Var(x) => Exp::Var(resolve(scope, x)),
+            e @ (Exp::Value(_) | Exp::Global(_)) => e,
+        }
+    }
+
+    fn bexp(&mut self, e: Exp, scope: &BTreeMap<Var, Var>) -> Box<Exp> {
+        Box::new(self.exp(e, scope))
+    }
+
+    // Picks the name a new binding of `x` goes by from here to the end of its scope.
+    fn bind(&mut self, x: Var, scope: &mut BTreeMap<Var, Var>) -> Var {
+        let name = if self.bound.contains(&x) || self.funcs.contains(&x) {
+            self.names.fresh(&format!("{}.", x))
+        } else {
+            x.clone()
+        };
+        self.bound.insert(x.clone());
+        scope.insert(x, name.clone());
+        name
+    }
+}
+
+// A name no binding in scope has is a function's, and stays as it is.
+fn resolve(scope: &BTreeMap<Var, Var>, x: Var) -> Var {
+    scope.get(&x).cloned().unwrap_or(x)
+}
@@ -3,19 +3,259 @@ use std::collections::BTreeMap;
 
 use pretty::RcDoc;
 
-use super::ToDoc;
+use super::read::{expected, malformed, read, Sexp};
+use super::{sexp, Parse, ToDoc};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Program<Body> {
-    pub funcs: BTreeMap<Var, Body>,
+    // See `global_addresses` for where each one lives.
+    pub globals: BTreeMap<Var, Global>,
+    pub funcs: BTreeMap<Var, Func<Body>>,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Func<Body> {
+    pub params: Vec<Var>,
+    pub body: Body,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Global {
+    // The given value when the program loads: one byte, or two for a word, low byte
+    // first.
+    Value(Value),
+    // This many bytes, all zero when the program loads, read and written as `a[i]`.
+    Array(usize),
+    // Constant bytes, such as a run of lamp patterns, that are only ever read.
+    Table(Vec<u8>),
+}
+
+impl Global {
+    pub fn size(&self) -> usize {
+        match self {
+            Global::Value(Value::Word(_)) => 2,
+            Global::Value(_) => 1,
+            Global::Array(len) => *len,
+            Global::Table(bytes) => bytes.len(),
+        }
+    }
+}
+
+impl ToDoc for Global {
+    fn to_doc(&self) -> RcDoc<'_> {
+        match self {
+            Global::Value(init) => init.to_doc(),
+            Global::Array(len) => RcDoc::text(format!("(array {})", len)),
+            Global::Table(bytes) => sexp(
+                RcDoc::text("table"),
+                bytes.iter().map(|b| RcDoc::text(b.to_string())),
+            ),
+        }
+    }
+}
+
+impl Parse for Global {
+    fn parse(sexp: &Sexp) -> Result<Global, String> {
+        match sexp.form() {
+            Some(("array", [len])) => Ok(Global::Array(parse_len(len)?)),
+            Some(("table", bytes)) if !bytes.is_empty() => bytes
+                .iter()
+                .map(|b| match Value::parse(b)? {
+                    Value::Int(n) => Ok(n),
+                    _ => expected("a byte", b),
+                })
+                .collect::<Result<_, _>>()
+                .map(Global::Table),
+            Some(("array" | "table", _)) => malformed(sexp),
+            _ => Value::parse(sexp).map(Global::Value),
+        }
+    }
+}
+
+// An array has at least one byte.
+pub fn parse_len(sexp: &Sexp) -> Result<usize, String> {
+    match sexp.atom().map(str::parse) {
+        Some(Ok(len)) if len > 0 => Ok(len),
+        _ => expected("an array length", sexp),
+    }
+}
+
+// The KENBAK keeps its registers in the first four bytes, the lamps at 0o200, the
+// overflow and carry flags at 0o201-0o203 and the switches at 0o377. Code goes
+// below the lamps; globals take the bytes between the flags and the switches.
+pub const GLOBALS_START: u8 = 0o204;
+pub const GLOBALS_END: u8 = 0o377;
+
+impl<Body> Program<Body> {
+    // Globals are laid out back to back in name order; an array's address is that
+    // of its first byte.
+    pub fn global_addresses(&self) -> Result<BTreeMap<Var, u8>, String> {
+        let room = usize::from(GLOBALS_END - GLOBALS_START);
+        let mut addresses = BTreeMap::new();
+        let mut next = 0;
+        for (name, global) in &self.globals {
+            addresses.insert(name.clone(), GLOBALS_START + next as u8);
+            next += global.size();
+            if next > room {
+                return Err(format!(
+                    "globals need more than the {} bytes from 0o{:o}",
+                    room, GLOBALS_START
+                ));
+            }
+        }
+        Ok(addresses)
+    }
+}
+
+// Printed as `(define name init)` lines for the globals, each followed by its
+// address in a comment, then `(define (name param ...) body)` forms, separated by
+// blank lines.
+impl<Body: ToDoc> ToDoc for Program<Body> {
+    fn to_doc(&self) -> RcDoc<'_> {
+        let addresses = self.global_addresses().unwrap_or_default();
+        let globals = self.globals.iter().map(|(name, global)| {
+            let global = sexp(RcDoc::text("define"), [RcDoc::text(name), global.to_doc()]);
+            match addresses.get(name) {
+                Some(address) => global.append(RcDoc::text(format!(" ; at 0o{:o}", address))),
+                None => global,
+            }
+        });
+        let globals =
+            (!self.globals.is_empty()).then(|| RcDoc::intersperse(globals, RcDoc::hardline()));
+        let funcs = self.funcs.iter().map(|(name, func)| {
+            let signature = sexp(RcDoc::text(name), func.params.iter().map(RcDoc::text));
+            sexp(
+                RcDoc::text("define ").append(signature),
+                [func.body.to_doc()],
+            )
+        });
+        RcDoc::intersperse(
+            globals.into_iter().chain(funcs),
+            RcDoc::hardline().append(RcDoc::hardline()),
+        )
+    }
+}
+
+impl<Body: Parse> Program<Body> {
+    pub fn read(text: &str) -> Result<Program<Body>, String> {
+        let mut globals = BTreeMap::new();
+        let mut funcs = BTreeMap::new();
+        for form in read(text)? {
+            let (signature, body) = match form.form() {
+                Some(("define", [name, init])) if name.atom().is_some() => {
+                    let name = parse_var(name)?;
+                    if globals.insert(name.clone(), Global::parse(init)?).is_some() {
+                        return Err(format!("{} is defined twice", name));
+                    }
+                    continue;
+                }
+                Some(("define", [signature, body])) => (signature, body),
+                _ => {
+                    return expected(
+                        "(define (name param ...) body) or (define name init)",
+                        &form,
+                    )
+                }
+            };
+            let (name, params) = match signature.list() {
+                Some([name, params @ ..]) => (parse_var(name)?, params),
+                _ => return expected("(name param ...)", signature),
+            };
+            let func = Func {
+                params: params.iter().map(parse_var).collect::<Result<_, _>>()?,
+                body: Body::parse(body).map_err(|err| format!("in {}: {}", name, err))?,
+            };
+            if funcs.insert(name.clone(), func).is_some() {
+                return Err(format!("{} is defined twice", name));
+            }
+        }
+        if let Some(name) = globals.keys().find(|name| funcs.contains_key(*name)) {
+            return Err(format!("{} is defined twice", name));
+        }
+        let program = Program { globals, funcs };
+        program.global_addresses()?;
+        Ok(program)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Op {
     Add,
     Sub,
     Eq,
     Neq,
+    // A byte with one bit tested, set or cleared. The bit is always a constant from 0,
+    // the low bit, to 7: the KENBAK's SKP and SET instructions carry it in the opcode.
+    BitTest,
+    BitSet,
+    BitClear,
+    // What the KENBAK's flag bytes record about x + y or x - y, so multi-byte
+    // arithmetic can be written: the carry out of an unsigned add and the borrow out
+    // of a subtraction, each 0 or 1, and whether a signed add overflows, a comparison.
+    Carry,
+    Borrow,
+    Overflow,
+    // `(word hi lo)` joins two bytes into a word; `(byte w i)` is a word's low byte
+    // for 0 and its high byte for 1, always a constant.
+    Word,
+    Byte,
+}
+
+impl Op {
+    // Comparisons give TRUE or FALSE, and only they can be branched on; everything
+    // else is arithmetic on bytes.
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, Op::Eq | Op::Neq | Op::BitTest | Op::Overflow)
+    }
+
+    // Whether the second operand picks a bit or byte, and so has to be a constant.
+    pub fn takes_position(&self) -> bool {
+        matches!(self, Op::BitTest | Op::BitSet | Op::BitClear | Op::Byte)
+    }
+
+    // What `lhs op rhs` gives, or `None` if it goes wrong at run time (adding TRUE, or
+    // a byte to a word). Bytes and words wrap like the KENBAK's registers.
+    pub fn apply(&self, lhs: &Value, rhs: &Value) -> Option<Value> {
+        match (lhs, self, rhs) {
+            (Value::Int(n), Op::Add, Value::Int(m)) => Some(Value::Int(n.wrapping_add(*m))),
+            (Value::Int(n), Op::Sub, Value::Int(m)) => Some(Value::Int(n.wrapping_sub(*m))),
+            (Value::Word(n), Op::Add, Value::Word(m)) => Some(Value::Word(n.wrapping_add(*m))),
+            (Value::Word(n), Op::Sub, Value::Word(m)) => Some(Value::Word(n.wrapping_sub(*m))),
+            (Value::Int(n), Op::BitSet, Value::Int(m)) if *m < 8 => Some(Value::Int(n | 1 << m)),
+            (Value::Int(n), Op::BitClear, Value::Int(m)) if *m < 8 => {
+                Some(Value::Int(n & !(1 << m)))
+            }
+            (Value::Int(n), Op::Carry, Value::Int(m)) => {
+                Some(Value::Int(n.checked_add(*m).is_none().into()))
+            }
+            (Value::Int(n), Op::Borrow, Value::Int(m)) => Some(Value::Int((n < m).into())),
+            (Value::Int(hi), Op::Word, Value::Int(lo)) => {
+                Some(Value::Word(u16::from_le_bytes([*lo, *hi])))
+            }
+            (Value::Word(w), Op::Byte, Value::Int(i)) if *i < 2 => {
+                Some(Value::Int(w.to_le_bytes()[usize::from(*i)]))
+            }
+            _ if self.is_comparison() => {
+                let holds = self.holds(lhs, rhs)?;
+                Some(if holds { Value::True } else { Value::False })
+            }
+            _ => None,
+        }
+    }
+
+    // Whether a comparison holds between two values, or `None` if it goes wrong at
+    // run time (testing a bit of TRUE) or `self` is not a comparison.
+    pub fn holds(&self, lhs: &Value, rhs: &Value) -> Option<bool> {
+        match (lhs, self, rhs) {
+            (_, Op::Eq, _) => Some(lhs == rhs),
+            (_, Op::Neq, _) => Some(lhs != rhs),
+            (Value::Int(n), Op::BitTest, Value::Int(b)) if *b < 8 => Some(n >> b & 1 == 1),
+            (Value::Int(n), Op::Overflow, Value::Int(m)) => {
+                Some((*n as i8).checked_add(*m as i8).is_none())
+            }
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Debug for Op {
@@ -25,30 +265,135 @@ impl fmt::Debug for Op {
             Op::Sub => write!(f, "-"),
             Op::Eq => write!(f, "=="),
             Op::Neq => write!(f, "!="),
+            Op::BitTest => write!(f, "bit_test"),
+            Op::BitSet => write!(f, "bit_set"),
+            Op::BitClear => write!(f, "bit_clear"),
+            Op::Carry => write!(f, "carry"),
+            Op::Borrow => write!(f, "borrow"),
+            Op::Overflow => write!(f, "overflow"),
+            Op::Word => write!(f, "word"),
+            Op::Byte => write!(f, "byte"),
         }
     }
 }
 
+impl ToDoc for Op {
+    fn to_doc(&self) -> RcDoc<'_> {
+        RcDoc::text(format!("{:?}", self))
+    }
+}
+
+impl Parse for Op {
+    fn parse(sexp: &Sexp) -> Result<Op, String> {
+        match sexp.atom() {
+            Some("+") => Ok(Op::Add),
+            Some("-") => Ok(Op::Sub),
+            Some("==") => Ok(Op::Eq),
+            Some("!=") => Ok(Op::Neq),
+            Some("bit_test") => Ok(Op::BitTest),
+            Some("bit_set") => Ok(Op::BitSet),
+            Some("bit_clear") => Ok(Op::BitClear),
+            Some("carry") => Ok(Op::Carry),
+            Some("borrow") => Ok(Op::Borrow),
+            Some("overflow") => Ok(Op::Overflow),
+            Some("word") => Ok(Op::Word),
+            Some("byte") => Ok(Op::Byte),
+            _ => expected("an operator", sexp),
+        }
+    }
+}
+
+// The second operand of an operator that `takes_position`.
+pub fn parse_position(op: &Op, sexp: &Sexp) -> Result<Value, String> {
+    let (limit, what) = match op {
+        Op::Byte => (2, "a byte number, 0 or 1"),
+        _ => (8, "a bit number from 0 to 7"),
+    };
+    match Value::parse(sexp) {
+        Ok(Value::Int(n)) if n < limit => Ok(Value::Int(n)),
+        _ => expected(what, sexp),
+    }
+}
+
 pub type Var = String;
 
-#[derive(Clone)]
+// Anything that cannot be read as a value or the return register names a variable.
+pub fn parse_var(sexp: &Sexp) -> Result<Var, String> {
+    match sexp.atom() {
+        Some(a) if !a.starts_with(|c: char| c.is_ascii_digit() || "#%&".contains(c)) => match a {
+            "TRUE" | "FALSE" | "define" | "begin" | "if" | "set!" | "exp" | "global"
+            | "set-global!" | "array" | "table" | "index" | "asm" | "lambda" | "_" => {
+                expected("a variable", sexp)
+            }
+            _ => Ok(a.to_string()),
+        },
+        _ => expected("a variable", sexp),
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Value {
     Int(u8),
+    // Two bytes, for counts past 255.
+    Word(u16),
     True,
     False,
+    // The entry address of a function, written `&f`. Calling one jumps indirectly
+    // through wherever it is stored.
+    Func(Var),
 }
 
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Int(n) => write!(f, "{}", n),
+            Value::Word(n) => write!(f, "{}w", n),
             Value::True => write!(f, "TRUE"),
             Value::False => write!(f, "FALSE"),
+            Value::Func(name) => write!(f, "&{}", name),
+        }
+    }
+}
+
+impl ToDoc for Value {
+    fn to_doc(&self) -> RcDoc<'_> {
+        RcDoc::text(format!("{:?}", self))
+    }
+}
+
+impl Parse for Value {
+    fn parse(sexp: &Sexp) -> Result<Value, String> {
+        match sexp.atom() {
+            Some("TRUE") => Ok(Value::True),
+            Some("FALSE") => Ok(Value::False),
+            // Lamp patterns read best in binary or octal, as `0b10100101` or `0o245`.
+            // A trailing `w` makes a word, as in `1000w`.
+            Some(a) if a.starts_with(|c: char| c.is_ascii_digit()) => {
+                let (digits, word) = match a.strip_suffix('w') {
+                    Some(digits) => (digits, true),
+                    None => (a, false),
+                };
+                let (digits, radix) = match (digits.strip_prefix("0b"), digits.strip_prefix("0o")) {
+                    (Some(bits), _) => (bits, 2),
+                    (_, Some(digits)) => (digits, 8),
+                    _ => (digits, 10),
+                };
+                match (word, u16::from_str_radix(digits, radix)) {
+                    (true, Ok(n)) => Ok(Value::Word(n)),
+                    (false, Ok(n)) if n < 256 => Ok(Value::Int(n as u8)),
+                    (true, Err(_)) => expected("a word", sexp),
+                    (false, _) => expected("a byte", sexp),
+                }
+            }
+            Some(a) if a.starts_with('&') => {
+                parse_var(&Sexp::Atom(a[1..].to_string())).map(Value::Func)
+            }
+            _ => expected("a value", sexp),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Triv {
     Value(Value),
     Var(Var),
@@ -56,29 +401,86 @@ pub enum Triv {
 }
 
 impl ToDoc for Triv {
-    fn to_doc(&self) -> RcDoc<()> {
+    fn to_doc(&self) -> RcDoc<'_> {
         match self {
-            Triv::Value(n) => RcDoc::text(format!("{:?}", n)),
+            Triv::Value(v) => v.to_doc(),
             Triv::Var(x) => RcDoc::text(x),
             Triv::Return => RcDoc::text("%ret"),
         }
     }
 }
 
-pub enum Loc {
-    Memory(u8),
-    A,
-    B,
-    X,
+impl Parse for Triv {
+    fn parse(sexp: &Sexp) -> Result<Triv, String> {
+        match sexp.atom() {
+            Some("%ret") => Ok(Triv::Return),
+            Some("TRUE" | "FALSE") => Value::parse(sexp).map(Triv::Value),
+            Some(a) if a.starts_with(|c: char| c.is_ascii_digit() || c == '&') => {
+                Value::parse(sexp).map(Triv::Value)
+            }
+            _ => parse_var(sexp).map(Triv::Var),
+        }
+    }
 }
 
-impl ToDoc for Loc {
-    fn to_doc(&self) -> RcDoc<()> {
-        match self {
-            Loc::Memory(n) => RcDoc::text(format!("@{:?}", n)),
-            Loc::A => RcDoc::text("%a"),
-            Loc::B => RcDoc::text("%b"),
-            Loc::X => RcDoc::text("%x"),
+// Hand-written instructions, kept as written all the way to the backend. The body
+// names its operands as `%0`, `%1`, ... counting the outputs first and then the
+// inputs, so passes are free to rename the variables bound to them. Anything else
+// the instructions touch is unknown, so a block is treated like a call to an
+// unknown function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Asm {
+    pub outputs: Vec<Var>,
+    pub inputs: Vec<Var>,
+    pub body: Vec<Sexp>,
+}
+
+impl ToDoc for Asm {
+    fn to_doc(&self) -> RcDoc<'_> {
+        let outputs = sexp(RcDoc::text("out"), self.outputs.iter().map(RcDoc::text));
+        let inputs = sexp(RcDoc::text("in"), self.inputs.iter().map(RcDoc::text));
+        let body = self.body.iter().map(|instr| RcDoc::text(instr.to_string()));
+        sexp(
+            RcDoc::text("asm"),
+            [outputs, inputs].into_iter().chain(body),
+        )
+    }
+}
+
+// `(asm (out x ...) (in y ...) instr ...)`
+impl Parse for Asm {
+    fn parse(sexp: &Sexp) -> Result<Asm, String> {
+        let (outputs, inputs, body) = match sexp.form() {
+            Some(("asm", [outputs, inputs, body @ ..])) => match (outputs.form(), inputs.form()) {
+                (Some(("out", outputs)), Some(("in", inputs))) => (outputs, inputs, body),
+                _ => return malformed(sexp),
+            },
+            _ => return expected("(asm (out var ...) (in var ...) instr ...)", sexp),
+        };
+        let asm = Asm {
+            outputs: outputs.iter().map(parse_var).collect::<Result<_, _>>()?,
+            inputs: inputs.iter().map(parse_var).collect::<Result<_, _>>()?,
+            body: body.to_vec(),
+        };
+        let operands = asm.outputs.len() + asm.inputs.len();
+        for instr in &asm.body {
+            check_operands(instr, operands)?;
         }
+        Ok(asm)
+    }
+}
+
+fn check_operands(sexp: &Sexp, operands: usize) -> Result<(), String> {
+    match sexp {
+        Sexp::Atom(a) => match a.strip_prefix('%').map(str::parse::<usize>) {
+            Some(Ok(n)) if n >= operands => Err(format!(
+                "asm operand {} out of range: there are {} operands",
+                a, operands
+            )),
+            _ => Ok(()),
+        },
+        Sexp::List(items) => items
+            .iter()
+            .try_for_each(|item| check_operands(item, operands)),
     }
 }
@@ -1,8 +1,43 @@
 use pretty::RcDoc;
 
+use self::read::Sexp;
+
 pub mod ast;
-pub mod registers;
+pub mod names;
+pub mod read;
 
 pub trait ToDoc {
-    fn to_doc(&self) -> RcDoc<()>;
+    fn to_doc(&self) -> RcDoc<'_>;
+}
+
+// The inverse of `ToDoc`: reading back what an IR prints gives an equal value.
+pub trait Parse: Sized {
+    fn parse(sexp: &Sexp) -> Result<Self, String>;
+}
+
+// `(head arg ...)` on one line if it fits, otherwise one argument per line.
+pub fn sexp<'a>(
+    head: RcDoc<'a, ()>,
+    args: impl IntoIterator<Item = RcDoc<'a, ()>>,
+) -> RcDoc<'a, ()> {
+    let args = args.into_iter().map(|arg| RcDoc::line().append(arg));
+    RcDoc::text("(")
+        .append(head)
+        .append(RcDoc::concat(args).nest(2))
+        .append(RcDoc::text(")"))
+        .group()
+}
+
+// `a[i]`, which reads back as `(index a i)`.
+pub fn index<'a>(array: &'a str, i: RcDoc<'a, ()>) -> RcDoc<'a, ()> {
+    RcDoc::text(array)
+        .append(RcDoc::text("["))
+        .append(i)
+        .append(RcDoc::text("]"))
+}
+
+// Checks the invariants an IR promises beyond what its types enforce, so a pass
+// that breaks them is caught where it ran rather than in whatever comes next.
+pub trait Verify {
+    fn verify(&self) -> Result<(), String>;
 }
@@ -0,0 +1,39 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::shared::ast::Var;
+
+// Where every name a pass makes up comes from. It starts out holding every name in
+// the source program and never hands out one it holds, so nothing the compiler
+// invents can collide with what the program calls its own variables, however
+// much they look like `tmp.1` or `x#2`.
+pub struct Names {
+    taken: BTreeSet<Var>,
+    // The last number tried after each prefix.
+    counters: BTreeMap<String, u32>,
+}
+
+impl Names {
+    pub fn new(taken: BTreeSet<Var>) -> Names {
+        Names {
+            taken,
+            counters: BTreeMap::new(),
+        }
+    }
+
+    // Takes `name` itself if nothing has it yet.
+    pub fn claim(&mut self, name: &str) -> bool {
+        self.taken.insert(name.to_string())
+    }
+
+    // The first of `{prefix}1`, `{prefix}2` and so on that nothing has.
+    pub fn fresh(&mut self, prefix: &str) -> Var {
+        let counter = self.counters.entry(prefix.to_string()).or_insert(0);
+        loop {
+            *counter += 1;
+            let name = format!("{}{}", prefix, counter);
+            if self.taken.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+
+use super::Parse;
+
+// The generic half of reading an IR back in: text to nested lists of atoms. Each
+// IR's `Parse` impls then give the lists meaning.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+}
+
+impl Sexp {
+    pub fn atom(&self) -> Option<&str> {
+        match self {
+            Sexp::Atom(a) => Some(a),
+            Sexp::List(_) => None,
+        }
+    }
+
+    pub fn list(&self) -> Option<&[Sexp]> {
+        match self {
+            Sexp::Atom(_) => None,
+            Sexp::List(items) => Some(items),
+        }
+    }
+
+    // A list whose first element is an atom, split into that atom and the rest.
+    pub fn form(&self) -> Option<(&str, &[Sexp])> {
+        match self.list()? {
+            [head, rest @ ..] => Some((head.atom()?, rest)),
+            [] => None,
+        }
+    }
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sexp::Atom(a) => write!(f, "{}", a),
+            Sexp::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+pub fn expected<T>(what: &str, found: &Sexp) -> Result<T, String> {
+    Err(format!("expected {}, found `{}`", what, found))
+}
+
+// A keyword form with the wrong number or kind of arguments.
+pub fn malformed<T>(sexp: &Sexp) -> Result<T, String> {
+    Err(format!("malformed `{}`", sexp))
+}
+
+pub fn parse_all<T: Parse>(sexps: &[Sexp]) -> Result<Vec<T>, String> {
+    sexps.iter().map(T::parse).collect()
+}
+
+// Reads every top-level form in `text`. `;` starts a comment that runs to the end
+// of the line, and `a[i]` is read as `(index a i)`.
+pub fn read(text: &str) -> Result<Vec<Sexp>, String> {
+    let mut stack: Vec<Vec<Sexp>> = vec![vec![]];
+    // The bracket that closes each open list.
+    let mut closers = vec![];
+    let mut atom = String::new();
+    let mut comment = false;
+    for c in text.chars() {
+        if comment {
+            comment = c != '\n';
+            continue;
+        }
+        if c.is_whitespace() || "()[];".contains(c) {
+            if !atom.is_empty() {
+                let done = Sexp::Atom(std::mem::take(&mut atom));
+                stack.last_mut().unwrap().push(done);
+            }
+        } else {
+            atom.push(c);
+        }
+        match c {
+            '(' => {
+                stack.push(vec![]);
+                closers.push(')');
+            }
+            '[' => {
+                let base = match stack.last_mut().unwrap().pop() {
+                    Some(base) => base,
+                    None => return Err("`[` with nothing before it to index".to_string()),
+                };
+                stack.push(vec![Sexp::Atom("index".to_string()), base]);
+                closers.push(']');
+            }
+            ')' | ']' => {
+                if closers.pop() != Some(c) {
+                    return Err(format!("unbalanced `{}`", c));
+                }
+                let list = stack.pop().unwrap();
+                stack.last_mut().unwrap().push(Sexp::List(list));
+            }
+            ';' => comment = true,
+            _ => (),
+        }
+    }
+    if !atom.is_empty() {
+        stack.last_mut().unwrap().push(Sexp::Atom(atom));
+    }
+    match closers.pop() {
+        None => Ok(stack.pop().unwrap()),
+        Some(')') => Err("unclosed `(`".to_string()),
+        Some(_) => Err("unclosed `[`".to_string()),
+    }
+}
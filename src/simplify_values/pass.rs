@@ -1,29 +1,47 @@
 use std::collections::BTreeMap;
 
 use crate::normalize_context::ast as input;
-use crate::shared::ast::{Program, Triv, Var};
+use crate::pipeline::Stage;
+use crate::shared::ast::{Func, Program, Triv, Var};
+use crate::shared::names::Names;
 use crate::simplify_values::ast;
 
-pub struct Pass {
-    counter: u32,
+pub struct Pass<'a> {
+    names: &'a mut Names,
 }
 
-impl Pass {
-    pub fn run(program: Program<input::Exp>) -> Program<ast::Exp> {
-        let Program { funcs } = program;
-        let mut pass = Pass { counter: 0 };
+impl Stage for Pass<'_> {
+    type Input = input::Exp;
+    type Output = ast::Exp;
+    const NAME: &'static str = "simplify_values";
+
+    fn run(program: Program<input::Exp>, names: &mut Names) -> Program<ast::Exp> {
+        Pass::run(program, names)
+    }
+}
+
+impl Pass<'_> {
+    pub fn run(program: Program<input::Exp>, names: &mut Names) -> Program<ast::Exp> {
+        let Program { globals, funcs } = program;
+        let mut pass = Pass { names };
         let mut output_funcs = BTreeMap::new();
-        for (name, body) in funcs {
-            output_funcs.insert(name, *pass.exp_block(Box::new(body)));
+        for (name, Func { params, body }) in funcs {
+            output_funcs.insert(
+                name,
+                Func {
+                    params,
+                    body: *pass.exp_block(body),
+                },
+            );
         }
         Program {
+            globals,
             funcs: output_funcs,
         }
     }
 
     fn make_tmp(&mut self) -> Var {
-        self.counter += 1;
-        format!("tmp.{}", self.counter)
+        self.names.fresh("tmp.")
     }
 
     fn exp(&mut self, block: &mut Vec<ast::Stmt>, e: input::Exp) -> ast::Exp {
@@ -56,23 +74,25 @@ impl Pass {
                 ast::Exp::Binop(lhs, op, rhs)
             }
             input::Exp::If(test, conseq, alt) => ast::Exp::If(
-                self.bpred(block, test),
-                self.exp_block(conseq),
-                self.exp_block(alt),
+                self.bpred(block, *test),
+                self.exp_block(*conseq),
+                self.exp_block(*alt),
             ),
             input::Exp::Value(v) => ast::Exp::Triv(Triv::Value(v)),
             input::Exp::Var(x) => ast::Exp::Triv(Triv::Var(x)),
+            input::Exp::Global(x) => ast::Exp::Global(x),
+            input::Exp::Index(a, i) => ast::Exp::Index(a, self.triv(block, *i)),
         }
     }
 
-    fn exp_block(&mut self, e: Box<input::Exp>) -> Box<ast::Exp> {
+    fn exp_block(&mut self, e: input::Exp) -> Box<ast::Exp> {
         let mut block = vec![];
-        let e = self.exp(&mut block, *e);
+        let e = self.exp(&mut block, e);
         Box::new(make_block(block, e))
     }
 
-    fn bexp(&mut self, block: &mut Vec<ast::Stmt>, e: Box<input::Exp>) -> Box<ast::Exp> {
-        Box::new(self.exp(block, *e))
+    fn bexp(&mut self, block: &mut Vec<ast::Stmt>, e: input::Exp) -> Box<ast::Exp> {
+        Box::new(self.exp(block, e))
     }
 
     fn pred(&mut self, block: &mut Vec<ast::Stmt>, p: input::Pred) -> ast::Pred {
@@ -105,23 +125,23 @@ impl Pass {
                 ast::Pred::Relop(lhs, op, rhs)
             }
             input::Pred::If(test, conseq, alt) => ast::Pred::If(
-                self.bpred(block, test),
-                self.pred_block(conseq),
-                self.pred_block(alt),
+                self.bpred(block, *test),
+                self.pred_block(*conseq),
+                self.pred_block(*alt),
             ),
             input::Pred::True => ast::Pred::True,
             input::Pred::False => ast::Pred::False,
         }
     }
 
-    fn pred_block(&mut self, e: Box<input::Pred>) -> Box<ast::Pred> {
+    fn pred_block(&mut self, e: input::Pred) -> Box<ast::Pred> {
         let mut block = vec![];
-        let e = self.pred(&mut block, *e);
+        let e = self.pred(&mut block, e);
         Box::new(make_pred_block(block, e))
     }
 
-    fn bpred(&mut self, block: &mut Vec<ast::Stmt>, p: Box<input::Pred>) -> Box<ast::Pred> {
-        Box::new(self.pred(block, *p))
+    fn bpred(&mut self, block: &mut Vec<ast::Stmt>, p: input::Pred) -> Box<ast::Pred> {
+        Box::new(self.pred(block, p))
     }
     /*
     fn exp(&mut self, e: input::Exp) -> ast::Exp {
@@ -173,15 +193,25 @@ impl Pass {
     fn stmt(&mut self, block: &mut Vec<ast::Stmt>, s: input::Stmt) {
         match s {
             input::Stmt::Exp(e) => {
-                let e = self.bexp(block, e);
+                let e = self.bexp(block, *e);
                 block.push(ast::Stmt::Exp(e));
             }
             input::Stmt::Let(x, rhs) => {
-                let rhs = self.bexp(block, rhs);
+                let rhs = self.bexp(block, *rhs);
                 block.push(ast::Stmt::Let(x, rhs));
             }
+            input::Stmt::SetGlobal(x, e) => {
+                let t = self.triv(block, *e);
+                block.push(ast::Stmt::SetGlobal(x, t));
+            }
+            input::Stmt::SetIndex(a, i, e) => {
+                let i = self.triv(block, *i);
+                let t = self.triv(block, *e);
+                block.push(ast::Stmt::SetIndex(a, i, t));
+            }
+            input::Stmt::Asm(asm) => block.push(ast::Stmt::Asm(asm)),
             input::Stmt::If(test, conseq, alt) => {
-                let test = self.bpred(block, test);
+                let test = self.bpred(block, *test);
                 block.push(ast::Stmt::If(
                     test,
                     self.stmt_block(conseq),
@@ -209,12 +239,16 @@ impl Pass {
             hoist @ (input::Exp::Call(_, _)
             | input::Exp::Seq(_, _)
             | input::Exp::Binop(_, _, _)
-            | input::Exp::If(_, _, _)) => match self.exp(block, hoist) {
+            | input::Exp::If(_, _, _)
+            | input::Exp::Global(_)
+            | input::Exp::Index(_, _)) => match self.exp(block, hoist) {
                 ast::Exp::Triv(t) => t,
                 exp @ (ast::Exp::Call(_, _)
                 | ast::Exp::Seq(_, _)
                 | ast::Exp::If(_, _, _)
-                | ast::Exp::Binop(_, _, _)) => {
+                | ast::Exp::Binop(_, _, _)
+                | ast::Exp::Global(_)
+                | ast::Exp::Index(_, _)) => {
                     let tmp = self.make_tmp();
                     block.push(ast::Stmt::Let(tmp.clone(), Box::new(exp)));
                     Triv::Var(tmp)
@@ -230,13 +264,15 @@ fn make_block(block: Vec<ast::Stmt>, exp: ast::Exp) -> ast::Exp {
     match exp {
         exp if block.is_empty() => exp,
         ast::Exp::Seq(stmts, base) => {
-            let stmts = block.into_iter().chain(stmts.into_iter()).collect();
+            let stmts = block.into_iter().chain(stmts).collect();
             make_block(stmts, *base)
         }
         exp @ (ast::Exp::Call(_, _)
         | ast::Exp::If(_, _, _)
         | ast::Exp::Triv(_)
-        | ast::Exp::Binop(_, _, _)) => ast::Exp::Seq(block, Box::new(exp)),
+        | ast::Exp::Binop(_, _, _)
+        | ast::Exp::Global(_)
+        | ast::Exp::Index(_, _)) => ast::Exp::Seq(block, Box::new(exp)),
     }
 }
 
@@ -244,7 +280,7 @@ fn make_pred_block(block: Vec<ast::Stmt>, exp: ast::Pred) -> ast::Pred {
     match exp {
         exp if block.is_empty() => exp,
         ast::Pred::Seq(stmts, base) => {
-            let stmts = block.into_iter().chain(stmts.into_iter()).collect();
+            let stmts = block.into_iter().chain(stmts).collect();
             make_pred_block(stmts, *base)
         }
         exp @ (ast::Pred::Call(_, _)
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::input::{Exp, Stmt};
+use crate::shared::ast::{Global, Op, Program, Value, Var};
+use crate::shared::ToDoc;
+
+// What a source value can be. Bytes and words are both numbers but do not mix: a
+// word takes two bytes of memory and its own instructions, so adding a byte to one
+// is a mistake rather than a widening. `Unknown` stands for a type not found yet,
+// numbered into `Checker::unknowns`.
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    Int,
+    Word,
+    Bool,
+    Func,
+    Unknown(usize),
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "a byte"),
+            Type::Word => write!(f, "a word"),
+            Type::Bool => write!(f, "a truth value"),
+            Type::Func => write!(f, "a function"),
+            Type::Unknown(_) => write!(f, "anything"),
+        }
+    }
+}
+
+fn value_type(v: &Value) -> Type {
+    match v {
+        Value::Int(_) => Type::Int,
+        Value::Word(_) => Type::Word,
+        Value::True | Value::False => Type::Bool,
+        Value::Func(_) => Type::Func,
+    }
+}
+
+// Gives every variable, parameter, result and global one type for the whole program
+// and rejects what cannot have one: operands of different widths, a word where the
+// bit operators want a byte, a byte where `byte` wants a word, and so on. A function
+// has one type for its parameters and one for its result, whoever calls it; a
+// function value is just a function, so what a call through one takes and gives is
+// only known from how its result is used. Anything still unknown at the end, like
+// the parameters of a function nothing calls, is left as it is. Calling anything
+// that is not a function is an error too: a variable holding a byte, or an array
+// element, which only ever holds one.
+pub fn check(program: &Program<Exp>) -> Result<(), String> {
+    let mut checker = Checker {
+        program,
+        func: "",
+        unknowns: vec![],
+        sigs: BTreeMap::new(),
+        numbers: vec![],
+    };
+    for (name, func) in &program.funcs {
+        let params = func.params.iter().map(|_| checker.unknown()).collect();
+        let result = checker.unknown();
+        checker.sigs.insert(name, (params, result));
+    }
+    for (name, func) in &program.funcs {
+        checker.func = name;
+        let (params, result) = checker.sigs[name].clone();
+        let locals = func.params.iter().zip(params).collect();
+        let body = checker.exp(&func.body, &locals)?;
+        checker.expect(&func.body, body, result)?;
+    }
+    for (func, e, t) in std::mem::take(&mut checker.numbers) {
+        checker.func = func;
+        checker.number(e, t)?;
+    }
+    Ok(())
+}
+
+struct Checker<'a> {
+    program: &'a Program<Exp>,
+    // The function being checked, for errors.
+    func: &'a str,
+    // What each unknown type has turned out to be, if anything yet.
+    unknowns: Vec<Option<Type>>,
+    // Each function's parameter and result types.
+    sigs: BTreeMap<&'a Var, (Vec<Type>, Type)>,
+    // Operands of `+` and `-` whose type was unknown when they were checked, to be
+    // checked again once everything is.
+    numbers: Vec<(&'a str, &'a Exp, Type)>,
+}
+
+impl<'a> Checker<'a> {
+    fn unknown(&mut self) -> Type {
+        self.unknowns.push(None);
+        Type::Unknown(self.unknowns.len() - 1)
+    }
+
+    fn resolve(&self, t: Type) -> Type {
+        match t {
+            Type::Unknown(i) => self.unknowns[i].map_or(t, |t| self.resolve(t)),
+            _ => t,
+        }
+    }
+
+    // Makes `found`, the type of `e`, the same as `wanted`.
+    fn expect(&mut self, e: &Exp, found: Type, wanted: Type) -> Result<(), String> {
+        match (self.resolve(found), self.resolve(wanted)) {
+            (found, wanted) if found == wanted => Ok(()),
+            (Type::Unknown(i), t) | (t, Type::Unknown(i)) => {
+                self.unknowns[i] = Some(t);
+                Ok(())
+            }
+            (found, wanted) => Err(format!(
+                "{}: {} is {} where {} is needed",
+                self.func,
+                e.to_doc().pretty(80),
+                found,
+                wanted
+            )),
+        }
+    }
+
+    fn number(&mut self, e: &'a Exp, t: Type) -> Result<(), String> {
+        match self.resolve(t) {
+            Type::Int | Type::Word => Ok(()),
+            Type::Unknown(_) => {
+                self.numbers.push((self.func, e, t));
+                Ok(())
+            }
+            t => Err(format!(
+                "{}: {} is {} where a byte or a word is needed",
+                self.func,
+                e.to_doc().pretty(80),
+                t
+            )),
+        }
+    }
+
+    fn global(&mut self, x: &Var) -> Type {
+        match self.program.globals.get(x) {
+            Some(Global::Value(v)) => value_type(v),
+            // Arrays and tables are only indexed, which hoist_arrays::check enforces.
+            _ => Type::Int,
+        }
+    }
+
+    // `locals` are the variables in scope and their types.
+    fn exp(&mut self, e: &'a Exp, locals: &BTreeMap<&'a Var, Type>) -> Result<Type, String> {
+        match e {
+            Exp::Call(subject, args) => {
+                let arg_types = args
+                    .iter()
+                    .map(|arg| self.exp(arg, locals))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let callee = match &**subject {
+                    Exp::Var(f) if !locals.contains_key(f) => self.sigs.get(f).cloned(),
+                    Exp::Value(Value::Func(f)) => self.sigs.get(f).cloned(),
+                    // Applied right where it is, a lambda's parameters are its arguments.
+                    Exp::Lambda(params, body) => {
+                        let mut locals = locals.clone();
+                        locals.extend(params.iter().zip(arg_types));
+                        return self.exp(body, &locals);
+                    }
+                    _ => None,
+                };
+                match callee {
+                    Some((params, result)) => {
+                        for ((arg, found), wanted) in args.iter().zip(arg_types).zip(params) {
+                            self.expect(arg, found, wanted)?;
+                        }
+                        Ok(result)
+                    }
+                    None => {
+                        let t = self.exp(subject, locals)?;
+                        self.expect(subject, t, Type::Func)?;
+                        Ok(self.unknown())
+                    }
+                }
+            }
+            Exp::Seq(stmts, body) => {
+                let mut locals = locals.clone();
+                for stmt in stmts {
+                    match stmt {
+                        Stmt::Exp(e) => {
+                            self.exp(e, &locals)?;
+                        }
+                        Stmt::Let(x, e) => {
+                            let t = self.exp(e, &locals)?;
+                            locals.insert(x, t);
+                        }
+                        Stmt::SetGlobal(x, e) => {
+                            let t = self.exp(e, &locals)?;
+                            let wanted = self.global(x);
+                            self.expect(e, t, wanted)?;
+                        }
+                        Stmt::SetIndex(_, i, e) => {
+                            let t = self.exp(i, &locals)?;
+                            self.expect(i, t, Type::Int)?;
+                            let t = self.exp(e, &locals)?;
+                            self.expect(e, t, Type::Int)?;
+                        }
+                        Stmt::Array(_, _) => (),
+                        // Each input and output is one register or byte of memory.
+                        Stmt::Asm(asm) => {
+                            for x in &asm.inputs {
+                                if let Some(&t) = locals.get(x) {
+                                    self.expect(&Exp::Var(x.clone()), t, Type::Int)?;
+                                }
+                            }
+                            locals.extend(asm.outputs.iter().map(|x| (x, Type::Int)));
+                        }
+                    }
+                }
+                self.exp(body, &locals)
+            }
+            Exp::Binop(lhs, op, rhs) => {
+                let lhs_type = self.exp(lhs, locals)?;
+                let rhs_type = self.exp(rhs, locals)?;
+                let (operands, result) = match op {
+                    Op::Add | Op::Sub => {
+                        self.number(lhs, lhs_type)?;
+                        self.number(rhs, rhs_type)?;
+                        self.expect(rhs, rhs_type, lhs_type)?;
+                        return Ok(lhs_type);
+                    }
+                    Op::Eq | Op::Neq => {
+                        self.expect(rhs, rhs_type, lhs_type)?;
+                        return Ok(Type::Bool);
+                    }
+                    Op::BitTest | Op::Overflow => ((Type::Int, Type::Int), Type::Bool),
+                    Op::BitSet | Op::BitClear | Op::Carry | Op::Borrow => {
+                        ((Type::Int, Type::Int), Type::Int)
+                    }
+                    Op::Word => ((Type::Int, Type::Int), Type::Word),
+                    Op::Byte => ((Type::Word, Type::Int), Type::Int),
+                };
+                self.expect(lhs, lhs_type, operands.0)?;
+                self.expect(rhs, rhs_type, operands.1)?;
+                Ok(result)
+            }
+            // Anything can be tested: only FALSE is false.
+            Exp::If(test, conseq, alt) => {
+                self.exp(test, locals)?;
+                let conseq_type = self.exp(conseq, locals)?;
+                let alt_type = self.exp(alt, locals)?;
+                self.expect(alt, alt_type, conseq_type)?;
+                Ok(conseq_type)
+            }
+            Exp::Index(_, i) => {
+                let t = self.exp(i, locals)?;
+                self.expect(i, t, Type::Int)?;
+                Ok(Type::Int)
+            }
+            Exp::Lambda(params, body) => {
+                let mut locals = locals.clone();
+                for x in params {
+                    let t = self.unknown();
+                    locals.insert(x, t);
+                }
+                self.exp(body, &locals)?;
+                Ok(Type::Func)
+            }
+            Exp::Var(x) => match locals.get(x) {
+                Some(&t) => Ok(t),
+                None if self.program.funcs.contains_key(x) => Ok(Type::Func),
+                // input::check reports it.
+                None => Ok(self.unknown()),
+            },
+            Exp::Global(x) => Ok(self.global(x)),
+            Exp::Value(v) => Ok(value_type(v)),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// Programs and options the compiler has to reject, each with the first line of the
+// error it gives.
+
+use std::fs;
+use std::process::Command;
+
+fn error(name: &str, source: &str) -> String {
+    error_with(name, source, &[])
+}
+
+fn error_with(name: &str, source: &str, options: &[&str]) -> String {
+    let path = format!("{}/{}.kb", env!("CARGO_TARGET_TMPDIR"), name);
+    fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_kenbak"))
+        .arg(&path)
+        .args(options)
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "{} compiled", name);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stderr
+        .trim()
+        .strip_prefix(&format!("error: {}: ", path))
+        .unwrap_or(&stderr)
+        .to_string()
+}
+
+#[test]
+fn mixed_widths() {
+    assert_eq!(
+        error("add", "(define (main x) (+ 1w 1))"),
+        "main: 1 is a byte where a word is needed"
+    );
+    assert_eq!(
+        error("compare", "(define (main y) (== (word y 0) 5))"),
+        "main: 5 is a byte where a word is needed"
+    );
+    assert_eq!(
+        error(
+            "param",
+            "(define (id x) x) (define (main y) (+ (id 1w) (id 1)))"
+        ),
+        "main: 1 is a byte where a word is needed"
+    );
+}
+
+#[test]
+fn word_operators() {
+    assert_eq!(
+        error("bit", "(define (main y) (bit_set 1w 3))"),
+        "main: 1w is a word where a byte is needed"
+    );
+    assert_eq!(
+        error("byte", "(define (main y) (byte 7 0))"),
+        "main: 7 is a byte where a word is needed"
+    );
+}
+
+#[test]
+fn arithmetic_on_functions() {
+    assert_eq!(
+        error("address", "(define (g) 1) (define (main y) (+ &g 1))"),
+        "main: &g is a function where a byte or a word is needed"
+    );
+}
+
+#[test]
+fn calls_of_non_functions() {
+    assert_eq!(
+        error("local", "(define (main y) (begin (set! x 5) (x 1)))"),
+        "main: x is a byte where a function is needed"
+    );
+    assert_eq!(
+        error("element", "(define a (array 2)) (define (main n) (a[0] n))"),
+        "main: a[0] is a byte where a function is needed"
+    );
+}
+
+#[test]
+fn unbound_variables() {
+    assert_eq!(error("bare", "(define (main n) y)"), "main: y is not bound");
+    assert_eq!(
+        error(
+            "asm",
+            "(define (main n) (begin (asm (out o) (in q) (LOAD A %1) (STORE A %0)) o))"
+        ),
+        "main: q is not bound"
+    );
+}
+
+#[test]
+fn escaping_closures() {
+    assert_eq!(
+        error(
+            "escaping",
+            "(define (add n) (lambda (x) (+ x n))) (define (main n) ((add n) ((add 1) n)))"
+        ),
+        "add: a lambda that captures variables and escapes can be evaluated more than \
+         once, and closures made from it would share what they captured"
+    );
+}
+
+#[test]
+fn bad_emit() {
+    let first_line = |e: String| e.lines().next().unwrap().to_string();
+    let source = "(define (main n) n)";
+    assert!(first_line(error_with("unknown", source, &["--emit=bogus"]))
+        .starts_with("error: no pass is called `bogus`"));
+    assert_eq!(
+        first_line(error_with(
+            "twice",
+            source,
+            &["--emit=build_cfg", "--emit=fold_constants"]
+        )),
+        "error: --emit given twice"
+    );
+}
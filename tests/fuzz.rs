@@ -0,0 +1,17 @@
+// A fixed-seed run of the fuzzer, so a pass that miscompiles any of these
+// programs fails here with the shrunk counterexample.
+
+use std::process::Command;
+
+#[test]
+fn fuzz() {
+    let output = Command::new(env!("CARGO_BIN_EXE_kenbak"))
+        .args(["--fuzz=2000", "--seed=1"])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
@@ -0,0 +1,93 @@
+// Snapshot tests: every `tests/golden/*.kb` program is compiled with each pass's
+// output printed, and the result is compared with the `.out` file next to it.
+// Run with BLESS=1 to rewrite the snapshots after an intended change.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn golden() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let bless = std::env::var_os("BLESS").is_some();
+    let mut sources = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "kb"))
+        .collect::<Vec<_>>();
+    sources.sort();
+    assert!(!sources.is_empty(), "no programs in {}", dir.display());
+
+    let mut failures = vec![];
+    for source in &sources {
+        let actual = compile(source);
+        let snapshot = source.with_extension("out");
+        if bless {
+            fs::write(&snapshot, &actual).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&snapshot).unwrap_or_default();
+        if expected != actual {
+            failures.push(format!(
+                "{}:\n{}",
+                snapshot.display(),
+                diff(&expected, &actual)
+            ));
+        }
+    }
+    if !failures.is_empty() {
+        panic!(
+            "{} snapshot(s) differ (rerun with BLESS=1 to accept):\n\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+    }
+}
+
+// Each pass's output, then the final listing.
+fn compile(source: &Path) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_kenbak"))
+        .arg(source)
+        .arg("--print-after=all")
+        .arg("--verify")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        return format!("{};; failed with {}\n", stderr, output.status);
+    }
+    format!("{};; final\n{}", stderr, stdout)
+}
+
+// A line diff from the longest common subsequence, with `-` for expected lines
+// that are missing and `+` for new ones.
+fn diff(expected: &str, actual: &str) -> String {
+    let old = expected.lines().collect::<Vec<_>>();
+    let new = actual.lines().collect::<Vec<_>>();
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out += &format!("+ {}\n", new[j]);
+            j += 1;
+        } else {
+            out += &format!("- {}\n", old[i]);
+            i += 1;
+        }
+    }
+    out
+}
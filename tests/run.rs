@@ -0,0 +1,42 @@
+// Programs compiled and run with `--run`, checked against what they return.
+
+use std::process::Command;
+
+fn run(source: &str, args: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_kenbak"))
+        .arg(format!(
+            "{}/tests/golden/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            source
+        ))
+        .arg(format!("--run={}", args))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+#[test]
+fn function_in_global() {
+    assert_eq!(run("handler.kb", "5"), "12");
+}
+
+#[test]
+fn variables_named_like_made_up_ones() {
+    assert_eq!(run("made_up_names.kb", "3,4"), "15");
+}
+
+#[test]
+fn loop_carried_swap() {
+    assert_eq!(run("swap.kb", "4"), "1");
+    assert_eq!(run("swap.kb", "5"), "2");
+}
+
+#[test]
+fn lambdas_that_never_escape() {
+    assert_eq!(run("kept_lambdas.kb", "4"), "14");
+}